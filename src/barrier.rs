@@ -1,5 +1,26 @@
 use std::sync::{Mutex,Condvar,Arc};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::thread;
+use std::panic::{self, AssertUnwindSafe};
+use std::time::{Duration, Instant};
+use std::hint;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+/// Returned by every waiter of a barrier that was broken, either by a timeout,
+/// a panicking participant or an explicit `break_barrier()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct BrokenBarrier;
+
+impl fmt::Display for BrokenBarrier {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "Barrier is broken.")
+    }
+}
+
+impl std::error::Error for BrokenBarrier {}
 
 struct Barrier {
     threshold: usize,
@@ -12,38 +33,147 @@ impl Barrier {
         Barrier {
             threshold: threshold,
             open: Condvar::new(),
-            state: Mutex::new(BarrierState::new(threshold)),
+            state: Mutex::new(BarrierState::new(threshold, None)),
         }
     }
 
-    fn wait(&self) -> bool {
-        let ret;
-        
+    /// Creates a barrier that runs `action` once per cycle on the last thread
+    /// to arrive, while all other waiters are still held. The barrier is
+    /// locked while the action runs, so it must not use the barrier itself.
+    /// A panicking action breaks the barrier.
+    fn with_action<A>(threshold: usize, action: A) -> Self
+        where A: FnMut() + Send + 'static
+    {
+        Barrier {
+            threshold,
+            open: Condvar::new(),
+            state: Mutex::new(BarrierState::new(threshold, Some(Box::new(action)))),
+        }
+    }
+
+    fn wait(&self) -> Result<bool, BrokenBarrier> {
+        self.wait_until(None)
+    }
+
+    /// Like `wait`, but breaks the barrier if it didn't open within `timeout`.
+    fn wait_timeout(&self, timeout: Duration) -> Result<bool, BrokenBarrier> {
+        self.wait_until(Some(Instant::now() + timeout))
+    }
+
+    fn wait_until(&self, deadline: Option<Instant>) -> Result<bool, BrokenBarrier> {
         match self.state.lock() {
-            Err(e) => panic!(format!("Locking failed: {}", e)),
+            Err(e) => panic!("Locking failed: {}", e),
             Ok(mut state) => {
                 if !state.valid {
-                    panic!("Waiting on invalid Barrier");
+                    return Err(BrokenBarrier);
                 }
 
                 let current_cycle = state.cycle;
                 state.counter -= 1;
                 
-                if state.counter <= 0 {
+                if state.counter == 0 {
+                    if let Some(ref mut action) = state.action {
+                        if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(action)) {
+                            state.valid = false;
+                            self.open.notify_all();
+                            drop(state);
+                            panic::resume_unwind(payload);
+                        }
+                    }
+
                     state.cycle = !state.cycle;
                     state.counter = self.threshold;
                     self.open.notify_all();
-                    ret = true;
+                    return Ok(true);
+                }
+
+                state.waiting += 1;
+
+                while current_cycle == state.cycle && state.valid {
+                    state = match deadline {
+                        None => self.open.wait(state).unwrap(),
+                        Some(deadline) => {
+                            let now = Instant::now();
+                            if now >= deadline {
+                                state.valid = false;
+                                self.open.notify_all();
+                                break;
+                            }
+                            self.open.wait_timeout(state, deadline - now).unwrap().0
+                        },
+                    };
+                }
+
+                state.waiting -= 1;
+                if state.waiting == 0 {
+                    // reset() might be waiting for the last waiter to leave
+                    self.open.notify_all();
+                }
+
+                // a cycle that completed counts, even if the barrier broke since
+                if current_cycle != state.cycle {
+                    Ok(false)
                 } else {
-                    while current_cycle == state.cycle {
-                        state = self.open.wait(state).unwrap();
-                    }
-                    ret = false;
+                    Err(BrokenBarrier)
+                }
+            },
+        }
+    }
+
+    /// Breaks the barrier: all current and future waiters get `BrokenBarrier`
+    /// until the barrier is `reset()`.
+    fn break_barrier(&self) {
+        match self.state.lock() {
+            Err(e) => panic!("Locking failed: {}", e),
+            Ok(mut state) => {
+                state.valid = false;
+                self.open.notify_all();
+            },
+        }
+    }
+
+    /// Breaks the barrier for any threads currently waiting, waits for them to
+    /// leave and makes the barrier usable again with a full count.
+    fn reset(&self) {
+        match self.state.lock() {
+            Err(e) => panic!("Locking failed: {}", e),
+            Ok(mut state) => {
+                state.valid = false;
+                self.open.notify_all();
+
+                while state.waiting > 0 {
+                    state = self.open.wait(state).unwrap();
                 }
+
+                state.counter = self.threshold;
+                state.valid = true;
             },
         }
+    }
+
+    fn is_broken(&self) -> bool {
+        match self.state.lock() {
+            Err(e) => panic!("Locking failed: {}", e),
+            Ok(state) => !state.valid,
+        }
+    }
+
+    /// Returns a guard that breaks the barrier if the thread holding it
+    /// panics, so the other participants don't wait for it forever.
+    fn break_on_panic(&self) -> BreakOnPanic<'_> {
+        BreakOnPanic { barrier: self }
+    }
+}
+
+struct BreakOnPanic<'a> {
+    barrier: &'a Barrier,
+}
 
-        ret
+impl<'a> Drop for BreakOnPanic<'a> {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            self.barrier.break_barrier();
+        }
     }
 }
 
@@ -51,33 +181,402 @@ struct BarrierState {
     counter: usize,
     cycle: bool,
     valid: bool,
+    waiting: usize,
+    action: Option<Box<dyn FnMut() + Send>>,
 }
 
 impl BarrierState {
-    fn new(counter: usize) -> Self {
+    fn new(counter: usize, action: Option<Box<dyn FnMut() + Send>>) -> Self {
         BarrierState {
             counter: counter,
             cycle: true,
             valid: true,
+            waiting: 0,
+            action,
+        }
+    }
+}
+
+/// A barrier whose number of parties can change between phases. Instead of
+/// flipping a `cycle` flag, every completed phase bumps the phase number, so
+/// threads can wait for the end of a specific phase.
+struct Phaser {
+    state: Mutex<PhaserState>,
+    advanced: Condvar,
+}
+
+impl Phaser {
+    fn new(parties: usize) -> Self {
+        Phaser {
+            state: Mutex::new(PhaserState {
+                parties,
+                unarrived: parties,
+                phase: 0,
+            }),
+            advanced: Condvar::new(),
+        }
+    }
+
+    /// Adds a party to the current phase and returns the phase number.
+    fn register(&self) -> u64 {
+        match self.state.lock() {
+            Err(e) => panic!("Locking failed: {}", e),
+            Ok(mut state) => {
+                state.parties += 1;
+                state.unarrived += 1;
+                state.phase
+            },
+        }
+    }
+
+    /// Arrives at the current phase without waiting for the others and
+    /// returns the phase number arrived at.
+    fn arrive(&self) -> u64 {
+        self.arrive_with(false)
+    }
+
+    /// Arrives at the current phase and leaves the phaser, so later phases
+    /// don't wait for this party anymore.
+    fn arrive_and_deregister(&self) -> u64 {
+        self.arrive_with(true)
+    }
+
+    /// Arrives and waits for the other parties, like `Barrier::wait`. Returns
+    /// the number of the phase that just started.
+    fn arrive_and_await_advance(&self) -> u64 {
+        let phase = self.arrive();
+        self.await_phase(phase)
+    }
+
+    /// Blocks until `phase` is over and returns the current phase number.
+    /// Returns immediately if the phaser already moved past `phase`, and
+    /// waits for the phases before it too if it hasn't started yet.
+    fn await_phase(&self, phase: u64) -> u64 {
+        match self.state.lock() {
+            Err(e) => panic!("Locking failed: {}", e),
+            Ok(mut state) => {
+                while state.phase <= phase {
+                    state = self.advanced.wait(state).unwrap();
+                }
+                state.phase
+            },
+        }
+    }
+
+    fn parties(&self) -> usize {
+        match self.state.lock() {
+            Err(e) => panic!("Locking failed: {}", e),
+            Ok(state) => state.parties,
+        }
+    }
+
+    fn arrive_with(&self, deregister: bool) -> u64 {
+        match self.state.lock() {
+            Err(e) => panic!("Locking failed: {}", e),
+            Ok(mut state) => {
+                if state.unarrived == 0 {
+                    panic!("Arriving at a Phaser without unarrived parties");
+                }
+
+                let phase = state.phase;
+                state.unarrived -= 1;
+                if deregister {
+                    state.parties -= 1;
+                }
+
+                if state.unarrived == 0 {
+                    state.phase += 1;
+                    state.unarrived = state.parties;
+                    self.advanced.notify_all();
+                }
+
+                phase
+            },
         }
     }
 }
 
+struct PhaserState {
+    parties: usize,
+    unarrived: usize,
+    phase: u64,
+}
+
+/// The operation every barrier implementation shares: block until all
+/// threads arrived and return `true` on exactly one of them per cycle.
+trait ThreadBarrier: Send + Sync {
+    fn wait(&self) -> bool;
+}
+
+impl ThreadBarrier for Barrier {
+    fn wait(&self) -> bool {
+        match Barrier::wait(self) {
+            Err(e) => panic!("Waiting on barrier failed: {}", e),
+            Ok(serial) => serial,
+        }
+    }
+}
+
+const SPIN_LIMIT: usize = 10_000;
+
+/// Busy-waits while `condition` holds. Gives up the time slice every
+/// `SPIN_LIMIT` iterations, so the thread we wait for gets to run even if
+/// there are more threads than cores.
+fn spin_while<C: Fn() -> bool>(condition: C) {
+    let mut spins = 0;
+
+    while condition() {
+        spins += 1;
+        if spins == SPIN_LIMIT {
+            spins = 0;
+            thread::yield_now();
+        } else {
+            hint::spin_loop();
+        }
+    }
+}
+
+/// Sense-reversing barrier that busy-waits. Cheapest for short phases, as
+/// long as there's a core for every thread.
+struct SpinBarrier {
+    threshold: usize,
+    counter: AtomicUsize,
+    sense: AtomicBool,
+}
+
+impl SpinBarrier {
+    fn new(threshold: usize) -> Self {
+        SpinBarrier {
+            threshold,
+            counter: AtomicUsize::new(threshold),
+            sense: AtomicBool::new(false),
+        }
+    }
+}
+
+impl ThreadBarrier for SpinBarrier {
+    fn wait(&self) -> bool {
+        // the sense can't flip before this thread arrived, so reading it
+        // first tells us which flip releases us
+        let sense = self.sense.load(Ordering::Acquire);
+
+        if self.counter.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.counter.store(self.threshold, Ordering::Relaxed);
+            self.sense.store(!sense, Ordering::Release);
+            true
+        } else {
+            spin_while(|| self.sense.load(Ordering::Acquire) == sense);
+            false
+        }
+    }
+}
+
+/// Spins for a while like `SpinBarrier` and blocks on a condition variable
+/// once that didn't pay off, so oversubscribed threads don't burn the CPU.
+struct AdaptiveBarrier {
+    threshold: usize,
+    counter: AtomicUsize,
+    cycle: AtomicUsize,
+    lock: Mutex<()>,
+    open: Condvar,
+}
+
+impl AdaptiveBarrier {
+    fn new(threshold: usize) -> Self {
+        AdaptiveBarrier {
+            threshold,
+            counter: AtomicUsize::new(threshold),
+            cycle: AtomicUsize::new(0),
+            lock: Mutex::new(()),
+            open: Condvar::new(),
+        }
+    }
+}
+
+impl ThreadBarrier for AdaptiveBarrier {
+    fn wait(&self) -> bool {
+        let cycle = self.cycle.load(Ordering::Acquire);
+
+        if self.counter.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.counter.store(self.threshold, Ordering::Relaxed);
+
+            // bump the cycle with the lock held, so no thread can check it and
+            // go to sleep in between
+            match self.lock.lock() {
+                Err(e) => panic!("Locking failed: {}", e),
+                Ok(_guard) => {
+                    self.cycle.store(cycle.wrapping_add(1), Ordering::Release);
+                    self.open.notify_all();
+                },
+            }
+            return true;
+        }
+
+        for _ in 0..SPIN_LIMIT {
+            if self.cycle.load(Ordering::Acquire) != cycle {
+                return false;
+            }
+            hint::spin_loop();
+        }
+
+        match self.lock.lock() {
+            Err(e) => panic!("Locking failed: {}", e),
+            Ok(mut guard) => {
+                while self.cycle.load(Ordering::Acquire) == cycle {
+                    guard = self.open.wait(guard).unwrap();
+                }
+            },
+        }
+
+        false
+    }
+}
+
+const TREE_RADIX: usize = 4;
+
+struct TreeNode {
+    parent: Option<usize>,
+    expected: usize,
+    counter: AtomicUsize,
+    released: AtomicUsize,
+}
+
+/// Combining-tree barrier: threads arrive in groups of `TREE_RADIX` at the
+/// leaves, and only the last one of each group climbs to the parent node. No
+/// counter or flag is shared by more than `TREE_RADIX` threads, which keeps
+/// the cache line traffic down for high thread counts.
+///
+/// Threads take a ticket when they arrive, which decides their leaf for this
+/// cycle, so they don't need to be the same ones every cycle. A node counts
+/// the cycles it released rather than flipping a flag, since a thread may
+/// already arrive at it for the next cycle before it was released for the
+/// current one.
+struct TreeBarrier {
+    threshold: usize,
+    next_ticket: AtomicUsize,
+    nodes: Vec<TreeNode>,
+}
+
+impl TreeBarrier {
+    fn new(threshold: usize) -> Self {
+        assert!(threshold > 0);
+
+        let mut nodes = Vec::new();
+        let mut level_start = 0;
+        let mut children = threshold;
+
+        // build the tree bottom up, leaves first
+        loop {
+            let level_len = children.div_ceil(TREE_RADIX);
+
+            for i in 0..level_len {
+                nodes.push(TreeNode {
+                    parent: None,
+                    expected: std::cmp::min(TREE_RADIX, children - i * TREE_RADIX),
+                    counter: AtomicUsize::new(0),
+                    released: AtomicUsize::new(0),
+                });
+            }
+
+            if level_len == 1 {
+                break;
+            }
+
+            let parent_start = level_start + level_len;
+            for i in 0..level_len {
+                nodes[level_start + i].parent = Some(parent_start + i / TREE_RADIX);
+            }
+
+            level_start = parent_start;
+            children = level_len;
+        }
+
+        for node in nodes.iter() {
+            node.counter.store(node.expected, Ordering::Relaxed);
+        }
+
+        TreeBarrier {
+            threshold,
+            next_ticket: AtomicUsize::new(0),
+            nodes,
+        }
+    }
+
+    fn arrive(&self, node_idx: usize, cycle: usize) -> bool {
+        let node = &self.nodes[node_idx];
+
+        if node.counter.fetch_sub(1, Ordering::AcqRel) == 1 {
+            // the whole group is here, so nobody else arrives at this node
+            // before the cycle is over
+            node.counter.store(node.expected, Ordering::Relaxed);
+
+            let serial = match node.parent {
+                None => true,
+                Some(parent) => self.arrive(parent, cycle),
+            };
+
+            // the whole tree arrived, release this node's group on the way
+            // down, unless the next cycle already got that far
+            node.released.fetch_max(cycle + 1, Ordering::Release);
+            serial
+        } else {
+            spin_while(|| node.released.load(Ordering::Acquire) <= cycle);
+            false
+        }
+    }
+}
+
+impl ThreadBarrier for TreeBarrier {
+    fn wait(&self) -> bool {
+        let ticket = self.next_ticket.fetch_add(1, Ordering::Relaxed);
+        self.arrive(ticket % self.threshold / TREE_RADIX, ticket / self.threshold)
+    }
+}
+
 const ARRAY_SIZE: usize = 6;
 const THREADS: usize = 5;
 const OUTLOOPS: usize = 10;
 const INLOOPS: usize = 1000;
 
+struct Config {
+    threads: usize,
+    outloops: usize,
+    inloops: usize,
+}
+
+impl Config {
+    fn from_args() -> Self {
+        let mut args = std::env::args().skip(1);
+        let mut next = |default: usize| match args.next() {
+            None => default,
+            Some(s) => match s.parse::<usize>() {
+                Ok(n) if n > 0 => n,
+                _ => abort_with_usage_message(),
+            },
+        };
+
+        Config {
+            threads: next(THREADS),
+            outloops: next(OUTLOOPS),
+            inloops: next(INLOOPS),
+        }
+    }
+}
+
+fn abort_with_usage_message() -> ! {
+    eprintln!("usage: barrier [threads] [outloops] [inloops]");
+    eprintln!("       barrier jacobi output.pgm [threads] [size]");
+    std::process::exit(1)
+}
+
 struct ThreadContext {
     array: Mutex<ThreadArray>,
-    barrier: Arc<Barrier>,
 }
 
 impl ThreadContext {
-    fn new(increment: u32, barrier: Arc<Barrier>) -> Self {
+    fn new(increment: u32) -> Self {
         ThreadContext {
             array: Mutex::new(ThreadArray::new(increment)),
-            barrier: barrier,
         }
     }
 }
@@ -102,21 +601,161 @@ impl ThreadArray {
     }
 }
 
+const JACOBI_SIZE: usize = 128;
+const JACOBI_EPSILON: f64 = 1e-4;
+const JACOBI_MAX_ITERATIONS: usize = 100_000;
+
+/// Heat diffusion on a square plate whose top edge is held at 1.0 and whose
+/// other edges are held at 0.0. Every thread owns a block of rows and keeps a
+/// second copy of it, so it reads one and writes the other without sharing
+/// them. Only the rows at the edges of the blocks are handed to the
+/// neighbouring threads, once per iteration.
+struct Jacobi {
+    size: usize,
+    grid: Vec<Vec<f64>>,
+    iterations: usize,
+    last_delta: f64,
+}
+
+struct JacobiState {
+    max_delta: f64,
+    last_delta: f64,
+    iterations: usize,
+    done: bool,
+}
+
+/// The first and last row of a thread's block, as of an even and an odd
+/// iteration. A thread may already publish the next iteration's rows while
+/// its neighbours still read the ones of this iteration.
+type BlockEdges = [Mutex<(Vec<f64>, Vec<f64>)>; 2];
+
+impl Jacobi {
+    /// Solves the plate with `threads` threads, each owning a block of rows.
+    fn solve(size: usize, threads: usize) -> Jacobi {
+        assert!(size >= 3);
+
+        let mut grid = (0..size).map(|i| {
+            vec![if i == 0 { 1.0 } else { 0.0 }; size]
+        }).collect::<Vec<_>>();
+
+        let interior = size - 2;
+        let threads = std::cmp::min(threads, interior);
+
+        let state = Arc::new(Mutex::new(JacobiState {
+            max_delta: 0.0,
+            last_delta: 0.0,
+            iterations: 0,
+            done: false,
+        }));
+
+        // the serial thread decides whether we're done, while everybody
+        // else is still held by the barrier
+        let action_state = state.clone();
+        let barrier = Barrier::with_action(threads, move || {
+            match action_state.lock() {
+                Err(e) => panic!("Unable to lock solver state: {}", e),
+                Ok(mut state) => {
+                    state.iterations += 1;
+                    state.last_delta = state.max_delta;
+                    state.max_delta = 0.0;
+                    state.done = state.last_delta < JACOBI_EPSILON
+                        || state.iterations >= JACOBI_MAX_ITERATIONS;
+                },
+            }
+        });
+
+        let edges = (0..threads).map(|_| {
+            let edge = || Mutex::new((vec![0.0; size], vec![0.0; size]));
+            [edge(), edge()]
+        }).collect::<Vec<_>>();
+
+        let blocks = (0..threads).map(|i| {
+            1 + i * interior / threads..1 + (i + 1) * interior / threads
+        }).collect::<Vec<_>>();
+        let around = blocks.iter().map(|rows| {
+            (grid[rows.start - 1].clone(), grid[rows.end].clone())
+        }).collect::<Vec<_>>();
+
+        // the top and bottom rows are held fixed and belong to nobody
+        let mut remaining = &mut grid[1..size - 1];
+
+        thread::scope(|scope| {
+            for (i, (rows, (above, below))) in blocks.into_iter().zip(around).enumerate() {
+                let (block, rest) = std::mem::take(&mut remaining).split_at_mut(rows.len());
+                remaining = rest;
+
+                let (barrier, state, edges) = (&barrier, &*state, &edges);
+                scope.spawn(move || {
+                    jacobi_routine(i, block, above, below, edges, barrier, state);
+                });
+            }
+        });
+
+        let (iterations, last_delta) = match state.lock() {
+            Err(e) => panic!("Unable to lock solver state: {}", e),
+            Ok(state) => (state.iterations, state.last_delta),
+        };
+
+        Jacobi { size, grid, iterations, last_delta }
+    }
+
+    fn grid(&self) -> &[Vec<f64>] {
+        &self.grid
+    }
+
+    /// Writes the grid as a binary greymap, hot areas white.
+    fn write_pgm(&self, path: &Path) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        write!(out, "P5\n{} {}\n255\n", self.size, self.size)?;
+
+        for row in self.grid() {
+            let pixels = row.iter()
+                .map(|v| (v.clamp(0.0, 1.0) * 255.0).round() as u8)
+                .collect::<Vec<_>>();
+            out.write_all(&pixels)?;
+        }
+
+        out.flush()
+    }
+}
+
 fn main() {
-    let mut handles = Vec::with_capacity(THREADS);
-    let barrier = Arc::new(Barrier::new(THREADS));
-    let mut contexts = Vec::with_capacity(THREADS);
+    if std::env::args().nth(1).as_deref() == Some("jacobi") {
+        jacobi_main();
+        return;
+    }
+
+    let config = Arc::new(Config::from_args());
+    let mut handles = Vec::with_capacity(config.threads);
+    let mut contexts = Vec::with_capacity(config.threads);
     
-    for i in 0..THREADS {
-        contexts.push(ThreadContext::new(i as u32, barrier.clone()));
+    for i in 0..config.threads {
+        contexts.push(ThreadContext::new(i as u32));
     }
 
     let contexts = Arc::new(contexts);
+
+    // once all threads finished an outer loop, bump every increment before
+    // any of them is released into the next one
+    let action_contexts = contexts.clone();
+    let barrier = Arc::new(Barrier::with_action(config.threads, move || {
+        for ctx in action_contexts.iter() {
+            match ctx.array.lock() {
+                Err(e) => panic!("Unable to lock mutex to increment: {}", e),
+                Ok(mut array) => {
+                    array.increment += 1;
+                },
+            }
+        }
+    }));
+
     for i in 0..contexts.len() {
         let thread_contexts = contexts.clone();
+        let barrier = barrier.clone();
+        let config = config.clone();
 
         handles.push(thread::spawn(move || {
-            thread_routine(thread_contexts, i as usize);
+            thread_routine(thread_contexts, barrier, &config, i);
         }));
     }
 
@@ -140,34 +779,484 @@ fn main() {
             },
         }
     }
+
+    timeout_demo();
+    phaser_demo();
+
+    println!();
+    println!("{:<16} {:>14}", "barrier", "wall time");
+    benchmark("mutex/condvar", Barrier::new(config.threads), &config);
+    benchmark("spin", SpinBarrier::new(config.threads), &config);
+    benchmark("adaptive", AdaptiveBarrier::new(config.threads), &config);
+    benchmark("tree", TreeBarrier::new(config.threads), &config);
 }
 
-fn thread_routine(contexts: Arc<Vec<ThreadContext>>, thread_num: usize) {
-    let my_ctx = &contexts[thread_num];
+/// Runs the book's two-waits-per-outer-loop pattern on `barrier` and prints
+/// the wall time it took. Every thread works on its own array, so the barrier
+/// is the only thing the threads share.
+fn benchmark<B: ThreadBarrier + 'static>(name: &str, barrier: B, config: &Config) {
+    let barrier = Arc::new(barrier);
+    let serial_count = Arc::new(AtomicUsize::new(0));
+    let (outloops, inloops) = (config.outloops, config.inloops);
 
-    for _ in 0..OUTLOOPS {
-        my_ctx.barrier.wait();
+    let start = Instant::now();
 
+    let handles = (0..config.threads).map(|i| {
+        let barrier = barrier.clone();
+        let serial_count = serial_count.clone();
+
+        thread::spawn(move || {
+            let mut array = ThreadArray::new(i as u32);
+
+            for _ in 0..outloops {
+                if barrier.wait() {
+                    serial_count.fetch_add(1, Ordering::Relaxed);
+                }
+
+                for _ in 0..inloops {
+                    for counter in 0..array.data.len() {
+                        array.data[counter] = array.data[counter].wrapping_add(array.increment);
+                    }
+                    hint::black_box(&mut array.data);
+                }
+
+                if barrier.wait() {
+                    serial_count.fetch_add(1, Ordering::Relaxed);
+                }
+                array.increment += 1;
+            }
+        })
+    }).collect::<Vec<_>>();
+
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    let elapsed = start.elapsed();
+    assert_eq!(serial_count.load(Ordering::Relaxed), 2 * outloops);
+    println!("{:<16} {:>14}", name, format!("{:?}", elapsed));
+}
+
+/// One participant never arrives: instead of hanging, the others time out,
+/// break the barrier and can reset it afterwards.
+fn timeout_demo() {
+    let barrier = Arc::new(Barrier::new(THREADS));
+
+    let handles = (0..THREADS - 1).map(|_| {
+        let barrier = barrier.clone();
+        thread::spawn(move || barrier.wait_timeout(Duration::from_millis(100)))
+    }).collect::<Vec<_>>();
+
+    for (thread_num, handle) in handles.into_iter().enumerate() {
+        match handle.join().unwrap() {
+            Err(e) => println!("{:02}: {}", thread_num, e),
+            Ok(_) => println!("{:02}: passed the barrier", thread_num),
+        }
+    }
+
+    barrier.reset();
+    println!("barrier broken after reset: {}", barrier.is_broken());
+}
+
+/// Workers join and leave between phases: worker N stays for N + 1 phases.
+fn phaser_demo() {
+    let phaser = Arc::new(Phaser::new(1));
+    let mut handles = Vec::with_capacity(THREADS);
+
+    for i in 0..THREADS {
+        phaser.register();
+
+        let phaser = phaser.clone();
+        handles.push(thread::spawn(move || {
+            for _ in 0..i {
+                phaser.arrive_and_await_advance();
+            }
+            phaser.arrive_and_deregister()
+        }));
+    }
+
+    let mut phase = 0;
+    while phaser.parties() > 1 {
+        phase = phaser.arrive_and_await_advance();
+    }
+
+    for (thread_num, handle) in handles.into_iter().enumerate() {
+        println!("{:02}: left in phase {}", thread_num, handle.join().unwrap());
+    }
+    println!("phaser advanced to phase {}", phase);
+}
+
+fn thread_routine(contexts: Arc<Vec<ThreadContext>>,
+                  barrier: Arc<Barrier>,
+                  config: &Config,
+                  thread_num: usize)
+{
+    let my_ctx = &contexts[thread_num];
+    let _guard = barrier.break_on_panic();
+
+    for _ in 0..config.outloops {
         match my_ctx.array.lock() {
             Err(e) => panic!(format!("Unable to lock mutex in inner loop: {}", e)),
             Ok(mut array) => {
-                for _ in 0..INLOOPS {
+                for _ in 0..config.inloops {
                     for counter in 0..array.data.len() {
-                        array.data[counter] += array.increment;
+                        array.data[counter] = array.data[counter].wrapping_add(array.increment);
                     }
                 }
             },
         }
 
-        if my_ctx.barrier.wait() {
-            for ctx in contexts.iter() {
-                match ctx.array.lock() {
-                    Err(e) => panic!(format!("Unable to lock mutex to increment: {}", e)),
-                    Ok(mut array) => {
-                        array.increment += 1;
-                    },
+        if let Err(e) = barrier.wait() {
+            panic!("Thread {} failed to wait on barrier: {}", thread_num, e);
+        }
+    }
+}
+
+/// Iterates on the rows in `block` until the serial thread says we're done.
+/// `above` and `below` are the rows just outside the block, which are kept
+/// up to date from the neighbouring blocks' edges.
+fn jacobi_routine(index: usize,
+                  block: &mut [Vec<f64>],
+                  mut above: Vec<f64>,
+                  mut below: Vec<f64>,
+                  edges: &[BlockEdges],
+                  barrier: &Barrier,
+                  state: &Mutex<JacobiState>)
+{
+    let _guard = barrier.break_on_panic();
+    let size = above.len();
+    let last = block.len() - 1;
+    let mut next = block.to_vec();
+
+    for iteration in 0.. {
+        let mut max_delta = 0.0f64;
+        for i in 0..block.len() {
+            let up = if i == 0 { &above } else { &block[i - 1] };
+            let down = if i == last { &below } else { &block[i + 1] };
+            let row = &block[i];
+
+            for j in 1..size - 1 {
+                let value = 0.25 * (up[j] + down[j] + row[j - 1] + row[j + 1]);
+                max_delta = max_delta.max((value - row[j]).abs());
+                next[i][j] = value;
+            }
+        }
+        block.swap_with_slice(&mut next);
+
+        let parity = iteration % 2;
+        match edges[index][parity].lock() {
+            Err(e) => panic!("Unable to lock block edges: {}", e),
+            Ok(mut edge) => {
+                edge.0.copy_from_slice(&block[0]);
+                edge.1.copy_from_slice(&block[last]);
+            },
+        }
+
+        match state.lock() {
+            Err(e) => panic!("Unable to lock solver state: {}", e),
+            Ok(mut state) => state.max_delta = state.max_delta.max(max_delta),
+        }
+
+        if let Err(e) = barrier.wait() {
+            panic!("Failed to wait on barrier: {}", e);
+        }
+
+        if index > 0 {
+            match edges[index - 1][parity].lock() {
+                Err(e) => panic!("Unable to lock block edges: {}", e),
+                Ok(edge) => above.copy_from_slice(&edge.1),
+            }
+        }
+        if index + 1 < edges.len() {
+            match edges[index + 1][parity].lock() {
+                Err(e) => panic!("Unable to lock block edges: {}", e),
+                Ok(edge) => below.copy_from_slice(&edge.0),
+            }
+        }
+
+        match state.lock() {
+            Err(e) => panic!("Unable to lock solver state: {}", e),
+            Ok(state) => if state.done {
+                break;
+            },
+        }
+    }
+}
+
+fn jacobi_main() {
+    let mut args = std::env::args().skip(2);
+    let output = match args.next() {
+        None => abort_with_usage_message(),
+        Some(s) => s,
+    };
+    let mut next = |default: usize| match args.next() {
+        None => default,
+        Some(s) => match s.parse::<usize>() {
+            Ok(n) if n > 0 => n,
+            _ => abort_with_usage_message(),
+        },
+    };
+    let threads = next(THREADS);
+    let size = std::cmp::max(next(JACOBI_SIZE), 3);
+
+    let start = Instant::now();
+    let jacobi = Jacobi::solve(size, threads);
+    let elapsed = start.elapsed();
+
+    println!("{}x{} grid, {} threads: {} iterations in {:?}, last change {:e}",
+             size, size, threads, jacobi.iterations, elapsed, jacobi.last_delta);
+
+    if let Err(e) = jacobi.write_pgm(Path::new(&output)) {
+        eprintln!("Unable to write {}: {}", output, e);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Barrier, BrokenBarrier, Phaser};
+    use super::{ThreadBarrier, SpinBarrier, AdaptiveBarrier, TreeBarrier};
+    use super::{Jacobi, JACOBI_EPSILON};
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn wait_timeout_breaks_barrier() {
+        let barrier = Barrier::new(2);
+
+        assert_eq!(barrier.wait_timeout(Duration::from_millis(10)), Err(BrokenBarrier));
+        assert!(barrier.is_broken());
+        assert_eq!(barrier.wait(), Err(BrokenBarrier));
+    }
+
+    #[test]
+    fn break_barrier_releases_waiters() {
+        let barrier = Arc::new(Barrier::new(3));
+
+        let handles = (0..2).map(|_| {
+            let barrier = barrier.clone();
+            thread::spawn(move || barrier.wait())
+        }).collect::<Vec<_>>();
+
+        thread::sleep(Duration::from_millis(20));
+        barrier.break_barrier();
+
+        for handle in handles {
+            assert_eq!(handle.join().unwrap(), Err(BrokenBarrier));
+        }
+    }
+
+    #[test]
+    fn panicking_participant_breaks_barrier() {
+        let barrier = Arc::new(Barrier::new(2));
+
+        let thread_barrier = barrier.clone();
+        let result = thread::spawn(move || {
+            let _guard = thread_barrier.break_on_panic();
+            panic!("participant failed");
+        }).join();
+
+        assert!(result.is_err());
+        assert_eq!(barrier.wait(), Err(BrokenBarrier));
+    }
+
+    #[test]
+    fn reset_recovers_broken_barrier() {
+        let barrier = Arc::new(Barrier::new(2));
+        barrier.break_barrier();
+        barrier.reset();
+        assert!(!barrier.is_broken());
+
+        let thread_barrier = barrier.clone();
+        let handle = thread::spawn(move || thread_barrier.wait());
+
+        let mine = barrier.wait().unwrap();
+        let theirs = handle.join().unwrap().unwrap();
+        assert!(mine != theirs);
+    }
+
+    #[test]
+    fn phaser_tracks_parties() {
+        let phaser = Phaser::new(1);
+        assert_eq!(phaser.arrive(), 0);
+        assert_eq!(phaser.await_phase(0), 1);
+
+        assert_eq!(phaser.register(), 1);
+        assert_eq!(phaser.arrive(), 1);
+        assert_eq!(phaser.arrive_and_deregister(), 1);
+        assert_eq!(phaser.parties(), 1);
+        assert_eq!(phaser.await_phase(1), 2);
+    }
+
+    #[test]
+    fn phaser_waits_for_registered_parties() {
+        let phaser = Arc::new(Phaser::new(1));
+        phaser.register();
+
+        let thread_phaser = phaser.clone();
+        let handle = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            thread_phaser.arrive_and_deregister()
+        });
+
+        assert_eq!(phaser.arrive_and_await_advance(), 1);
+        assert_eq!(handle.join().unwrap(), 0);
+        assert_eq!(phaser.parties(), 1);
+    }
+
+    #[test]
+    fn phaser_waits_for_future_phase() {
+        let phaser = Arc::new(Phaser::new(1));
+
+        let thread_phaser = phaser.clone();
+        let handle = thread::spawn(move || thread_phaser.await_phase(2));
+
+        // phases 0 and 1 end without the waiter being released
+        for phase in 0..2 {
+            thread::sleep(Duration::from_millis(20));
+            assert!(!handle.is_finished());
+            assert_eq!(phaser.arrive(), phase);
+        }
+
+        assert_eq!(phaser.arrive(), 2);
+        assert_eq!(handle.join().unwrap(), 3);
+    }
+
+    #[test]
+    fn action_runs_once_per_cycle_before_release() {
+        let count = Arc::new(AtomicUsize::new(0));
+
+        let action_count = count.clone();
+        let barrier = Arc::new(Barrier::with_action(3, move || {
+            action_count.fetch_add(1, Ordering::SeqCst);
+        }));
+
+        let handles = (0..3).map(|_| {
+            let barrier = barrier.clone();
+            let count = count.clone();
+            thread::spawn(move || {
+                for cycle in 1..11 {
+                    barrier.wait().unwrap();
+                    assert!(count.load(Ordering::SeqCst) >= cycle);
+                }
+            })
+        }).collect::<Vec<_>>();
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(count.load(Ordering::SeqCst), 10);
+    }
+
+    #[test]
+    fn panicking_action_breaks_barrier() {
+        let barrier = Arc::new(Barrier::with_action(2, || panic!("action failed")));
+
+        let thread_barrier = barrier.clone();
+        let handle = thread::spawn(move || thread_barrier.wait());
+
+        thread::sleep(Duration::from_millis(20));
+        assert!(panic::catch_unwind(AssertUnwindSafe(|| barrier.wait())).is_err());
+        assert_eq!(handle.join().unwrap(), Err(BrokenBarrier));
+        assert!(barrier.is_broken());
+    }
+
+    fn one_serial_thread_per_cycle<B: ThreadBarrier + 'static>(barrier: B, threads: usize) {
+        let barrier = Arc::new(barrier);
+        let serial_count = Arc::new(AtomicUsize::new(0));
+        let arrived = Arc::new(AtomicUsize::new(0));
+
+        let handles = (0..threads).map(|_| {
+            let barrier = barrier.clone();
+            let serial_count = serial_count.clone();
+            let arrived = arrived.clone();
+
+            thread::spawn(move || {
+                for cycle in 1..21 {
+                    arrived.fetch_add(1, Ordering::SeqCst);
+                    if barrier.wait() {
+                        serial_count.fetch_add(1, Ordering::SeqCst);
+                    }
+                    assert!(arrived.load(Ordering::SeqCst) >= cycle * threads);
                 }
+            })
+        }).collect::<Vec<_>>();
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(serial_count.load(Ordering::SeqCst), 20);
+    }
+
+    #[test]
+    fn spin_barrier() {
+        one_serial_thread_per_cycle(SpinBarrier::new(4), 4);
+    }
+
+    #[test]
+    fn adaptive_barrier() {
+        one_serial_thread_per_cycle(AdaptiveBarrier::new(4), 4);
+    }
+
+    #[test]
+    fn tree_barrier() {
+        one_serial_thread_per_cycle(TreeBarrier::new(1), 1);
+        one_serial_thread_per_cycle(TreeBarrier::new(9), 9);
+        one_serial_thread_per_cycle(TreeBarrier::new(17), 17);
+    }
+
+    #[test]
+    fn tree_barrier_with_changing_threads() {
+        let barrier = Arc::new(TreeBarrier::new(6));
+
+        // every round runs on fresh threads
+        for _ in 0..10 {
+            let serial_count = Arc::new(AtomicUsize::new(0));
+
+            let handles = (0..6).map(|_| {
+                let barrier = barrier.clone();
+                let serial_count = serial_count.clone();
+
+                thread::spawn(move || {
+                    for _ in 0..5 {
+                        if barrier.wait() {
+                            serial_count.fetch_add(1, Ordering::SeqCst);
+                        }
+                    }
+                })
+            }).collect::<Vec<_>>();
+
+            for handle in handles {
+                handle.join().unwrap();
             }
+            assert_eq!(serial_count.load(Ordering::SeqCst), 5);
         }
     }
+
+    #[test]
+    fn jacobi_converges_to_symmetric_plate() {
+        let jacobi = Jacobi::solve(16, 3);
+        let grid = jacobi.grid();
+
+        assert!(jacobi.last_delta < JACOBI_EPSILON);
+        assert!(grid[0].iter().all(|&v| v == 1.0));
+        assert!(grid[15].iter().all(|&v| v == 0.0));
+
+        for row in grid[1..15].iter() {
+            for j in 1..15 {
+                assert!(row[j] > 0.0 && row[j] < 1.0);
+                assert!((row[j] - row[15 - j]).abs() < 1e-9);
+            }
+        }
+
+        // heat flows in from the top, so it falls off row by row
+        assert!(grid[1][8] > grid[7][8] && grid[7][8] > grid[14][8]);
+
+        // the blocks only share their edge rows, which mustn't change the result
+        assert_eq!(Jacobi::solve(16, 1).grid(), grid);
+        assert_eq!(Jacobi::solve(16, 14).grid(), grid);
+    }
 }
@@ -1,243 +1,1568 @@
-use std::path::PathBuf;
-use std::sync::{Mutex,Condvar,Arc};
-use std::io::{Read,Write};
-use std::thread;
-use std::fs::{File,read_dir,symlink_metadata};
-use std::os::unix::fs::FileTypeExt;
+extern crate regex;
+extern crate globset;
 
-type Messages = Arc<Mutex<Vec<String>>>;
+use std::path::{Path,PathBuf};
+use std::sync::{Mutex,Condvar,Arc};
+use std::sync::mpsc::{channel,Sender,Receiver};
+use std::io::{self,Read,Write};
+use std::thread::{self, JoinHandle};
+use std::panic::{self, AssertUnwindSafe};
+use std::fs::{File,DirEntry,read_dir,metadata,symlink_metadata};
+use std::os::unix::fs::{FileTypeExt,MetadataExt};
+use std::fmt;
+use std::collections::{VecDeque,HashSet};
+use regex::bytes::{RegexSet, RegexSetBuilder};
+use globset::{Glob, GlobBuilder, GlobMatcher, GlobSet, GlobSetBuilder};
 
-struct WorkItem {
+/// A path the crew couldn't search, and why.
+struct PathError {
     path: PathBuf,
-    search: String,
+    error: io::Error,
+}
+
+impl fmt::Display for PathError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "{}: {}", self.path.display(), self.error)
+    }
+}
+
+/// The patterns a file is searched for. A file matches if it contains any of
+/// them, or with `all`, every one of them.
+struct Search {
+    patterns: RegexSet,
+    all: bool,
+}
+
+impl Search {
+    /// Compiles `patterns` as regular expressions, or as plain strings if
+    /// `literal`.
+    fn new(patterns: &[String], literal: bool, ignore_case: bool, all: bool) -> Result<Self, regex::Error> {
+        let patterns = if literal {
+            patterns.iter().map(|p| regex::escape(p)).collect()
+        } else {
+            patterns.to_vec()
+        };
+
+        let patterns = RegexSetBuilder::new(patterns)
+            .case_insensitive(ignore_case)
+            .build()?;
+
+        Ok(Search { patterns, all })
+    }
+
+    /// Hands the lines of a file that match any pattern to `report`, each
+    /// with up to `before` lines of context before and `after` lines after
+    /// it, in batches of about `BATCH_LINES` lines. With `all`, none unless
+    /// every pattern matched somewhere in the file, so the lines are kept
+    /// until they have.
+    fn find<R: Read, F: FnMut(Vec<Line>)>(&self, lines: &mut LineReader<R>, before: usize, after: usize,
+                                          mut report: F) -> io::Result<()> {
+        let mut collector = Collector::new(before, after);
+        let mut matched = vec![false; self.patterns.len()];
+        // the line being read, which may still have pieces to come
+        let mut current: Option<Line> = None;
+
+        loop {
+            let continued = lines.is_cut();
+            let text = match lines.next_line()? {
+                None => break,
+                Some(text) => text,
+            };
+
+            let matches = self.patterns.matches(text);
+            for i in matches.iter() {
+                matched[i] = true;
+            }
+
+            // report a long line that was cut into pieces only once, with
+            // the piece that matched if any did
+            if continued {
+                if let Some(ref mut line) = current {
+                    if matches.matched_any() && !line.matched {
+                        line.text = text.to_vec();
+                        line.offset = lines.line_offset();
+                        line.matched = true;
+                    }
+                    continue;
+                }
+            } else if let Some(line) = current.take() {
+                collector.add(line);
+                if collector.found.len() >= BATCH_LINES && (!self.all || matched.iter().all(|&m| m)) {
+                    report(std::mem::take(&mut collector.found));
+                }
+            }
+
+            if !matches.matched_any() && !collector.wants_context() {
+                continue;
+            }
+
+            let text = text.to_vec();
+            current = Some(Line {
+                number: lines.line_number(),
+                offset: lines.line_offset(),
+                text,
+                matched: matches.matched_any(),
+            });
+        }
+
+        if let Some(line) = current {
+            collector.add(line);
+        }
+
+        if !collector.found.is_empty() && (!self.all || matched.iter().all(|&m| m)) {
+            report(collector.found);
+        }
+
+        Ok(())
+    }
+}
+
+/// How many lines `Search::find` collects before reporting them.
+const BATCH_LINES: usize = 1024;
+
+/// Sorts the lines `Search::find` read into matches and the context around
+/// them, keeping the last `before` lines in case a match follows.
+struct Collector {
+    found: Vec<Line>,
+    previous: VecDeque<Line>,
+    before: usize,
+    after: usize,
+    trailing: usize,
+}
+
+impl Collector {
+    fn new(before: usize, after: usize) -> Self {
+        Collector {
+            found: Vec::new(),
+            previous: VecDeque::with_capacity(before),
+            before,
+            after,
+            trailing: 0,
+        }
+    }
+
+    /// Whether the next line is needed even if it doesn't match.
+    fn wants_context(&self) -> bool {
+        self.before > 0 || self.trailing > 0
+    }
+
+    fn add(&mut self, line: Line) {
+        if line.matched {
+            self.found.extend(self.previous.drain(..));
+            self.found.push(line);
+            self.trailing = self.after;
+        } else if self.trailing > 0 {
+            self.found.push(line);
+            self.trailing -= 1;
+        } else if self.before > 0 {
+            if self.previous.len() == self.before {
+                self.previous.pop_front();
+            }
+            self.previous.push_back(line);
+        }
+    }
+}
+
+impl fmt::Display for Search {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        for (i, pattern) in self.patterns.patterns().iter().enumerate() {
+            if i > 0 {
+                write!(f, "{}", if self.all { " and " } else { " or " })?;
+            }
+            write!(f, "{:?}", pattern)?;
+        }
+        Ok(())
+    }
+}
+
+/// A line of a file that matched, or that is context around a match.
+struct Line {
+    number: u64,
+    offset: u64,
+    text: Vec<u8>,
+    matched: bool,
+}
+
+/// Formats the lines found in `path` like `grep -n`, one per line without a
+/// newline after the last. With `context`, groups of lines that aren't
+/// adjacent are set apart by `--`. Adds the byte offsets if asked to.
+fn format_lines(path: &Path, lines: &[Line], context: bool, byte_offset: bool) -> String {
+    let mut formatted = String::new();
+    let mut previous = None;
+
+    for line in lines {
+        if let Some(n) = previous {
+            formatted.push('\n');
+            if context && line.number > n + 1 {
+                formatted.push_str("--\n");
+            }
+        }
+        previous = Some(line.number);
+
+        let separator = if line.matched { ':' } else { '-' };
+        formatted.push_str(&format!("{}{}{}{}", path.display(), separator, line.number, separator));
+        if byte_offset {
+            formatted.push_str(&format!("{}{}", line.offset, separator));
+        }
+        formatted.push_str(&String::from_utf8_lossy(&line.text));
+    }
+
+    formatted
+}
+
+/// How files are searched and matches reported, shared by all work items.
+struct Config {
+    search: Search,
+    // search files that look binary as if they were text
+    binary: bool,
+    // lines of context before and after a matching line
+    before: usize,
+    after: usize,
+    byte_offset: bool,
+    // search where symlinks point to, instead of skipping them
+    follow: bool,
+    // don't descend into directories on other file systems
+    one_file_system: bool,
+    filter: Filter,
+}
+
+/// Which of the paths found below the searched ones are searched too. The
+/// searched paths themselves always are, unless they are too large.
+struct Filter {
+    // if given, only files whose name matches are searched
+    include: Option<GlobSet>,
+    // files and directories whose name matches are skipped
+    exclude: GlobSet,
+    // skip files and directories whose name starts with a dot
+    skip_hidden: bool,
+    // skip what .gitignore and .ignore files ignore
+    ignore_files: bool,
+    // how many directories deep to descend
+    max_depth: Option<usize>,
+    // skip files larger than this many bytes
+    max_file_size: Option<u64>,
+}
+
+impl Filter {
+    /// Builds the include and exclude glob sets, where no include globs mean
+    /// every file is included.
+    fn globs(include: &[String], exclude: &[String]) -> Result<(Option<GlobSet>, GlobSet), globset::Error> {
+        let build = |globs: &[String]| {
+            let mut builder = GlobSetBuilder::new();
+            for glob in globs {
+                builder.add(Glob::new(glob)?);
+            }
+            builder.build()
+        };
+
+        let included = if include.is_empty() { None } else { Some(build(include)?) };
+        Ok((included, build(exclude)?))
+    }
+
+    /// Whether an entry of a directory that is being searched is searched.
+    fn keeps(&self, path: &Path, is_dir: bool, ignores: Option<&Ignores>) -> bool {
+        let name = match path.file_name() {
+            None => return true,
+            Some(name) => name,
+        };
+
+        if self.skip_hidden && name.to_string_lossy().starts_with('.') {
+            return false;
+        }
+        if self.exclude.is_match(name) {
+            return false;
+        }
+        if !is_dir {
+            if let Some(ref include) = self.include {
+                if !include.is_match(name) {
+                    return false;
+                }
+            }
+        }
+
+        match ignores {
+            Some(ignores) => !ignores.ignores(path, is_dir),
+            None => true,
+        }
+    }
+}
+
+impl Default for Filter {
+    fn default() -> Self {
+        Filter {
+            include: None,
+            exclude: GlobSet::empty(),
+            skip_hidden: false,
+            ignore_files: false,
+            max_depth: None,
+            max_file_size: None,
+        }
+    }
+}
+
+const IGNORE_FILES: [&str; 2] = [".gitignore", ".ignore"];
+
+/// One line of an ignore file. Globs with a slash are matched against the
+/// path below the ignore file's directory, the others against the name.
+struct IgnoreRule {
+    glob: GlobMatcher,
+    anchored: bool,
+    // the rule re-includes what an earlier one ignored
+    negated: bool,
+    dir_only: bool,
+}
+
+/// The rules of the ignore files in a directory, and in the directories
+/// above it that were searched.
+struct Ignores {
+    dir: PathBuf,
+    rules: Vec<IgnoreRule>,
+    parent: Option<Arc<Ignores>>,
+}
+
+impl Ignores {
+    /// Reads the ignore files in `dir`. Returns `parent` if there are none.
+    fn read(dir: &Path, parent: Option<Arc<Ignores>>) -> Result<Option<Arc<Ignores>>, PathError> {
+        let mut rules = Vec::new();
+
+        for name in IGNORE_FILES.iter() {
+            let path = dir.join(name);
+            let mut text = String::new();
+            match File::open(&path).and_then(|mut f| f.read_to_string(&mut text)) {
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(PathError { path, error: e }),
+                Ok(_) => {},
+            }
+
+            match Ignores::parse(&text) {
+                Err(e) => return Err(PathError { path, error: io::Error::new(io::ErrorKind::InvalidData, e) }),
+                Ok(mut r) => rules.append(&mut r),
+            }
+        }
+
+        if rules.is_empty() {
+            return Ok(parent);
+        }
+
+        Ok(Some(Arc::new(Ignores { dir: dir.to_path_buf(), rules, parent })))
+    }
+
+    fn parse(text: &str) -> Result<Vec<IgnoreRule>, globset::Error> {
+        let mut rules = Vec::new();
+
+        for line in text.lines() {
+            let line = line.trim_end();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let negated = line.starts_with('!');
+            let line = line.trim_start_matches('!');
+            let dir_only = line.ends_with('/');
+            let line = line.trim_end_matches('/');
+            let anchored = line.contains('/');
+            let line = line.trim_start_matches('/');
+
+            let glob = GlobBuilder::new(line).literal_separator(true).build()?.compile_matcher();
+            rules.push(IgnoreRule { glob, anchored, negated, dir_only });
+        }
+
+        Ok(rules)
+    }
+
+    /// Whether `path` is ignored. The last rule that matches decides, and
+    /// the rules of deeper directories come after those of their parents.
+    fn ignores(&self, path: &Path, is_dir: bool) -> bool {
+        let mut ignores = Some(self);
+
+        while let Some(current) = ignores {
+            let matching = current.rules.iter().rev().find(|rule| {
+                if rule.dir_only && !is_dir {
+                    return false;
+                }
+                if rule.anchored {
+                    path.strip_prefix(&current.dir).map(|p| rule.glob.is_match(p)).unwrap_or(false)
+                } else {
+                    path.file_name().map(|name| rule.glob.is_match(name)).unwrap_or(false)
+                }
+            });
+            if let Some(rule) = matching {
+                return !rule.negated;
+            }
+
+            ignores = current.parent.as_deref();
+        }
+
+        false
+    }
+}
+
+const CHUNK_SIZE: usize = 64 * 1024;
+const MAX_LINE_LENGTH: usize = 1024 * 1024;
+const LONG_LINE_OVERLAP: usize = 4 * 1024;
+
+/// Reads a file line by line in chunks, so that only a chunk and the line
+/// being read are in memory. Lines longer than `max_line` are cut into
+/// pieces that overlap by `overlap` bytes, to still find matches that
+/// aren't longer than the overlap where a line is cut. `offset` is where in
+/// the file `buffer[start]` is.
+struct LineReader<R> {
+    reader: R,
+    buffer: Vec<u8>,
+    start: usize,
+    end: usize,
+    eof: bool,
+    offset: u64,
+    line: u64,
+    line_offset: u64,
+    cut: bool,
+    chunk_size: usize,
+    max_line: usize,
+    overlap: usize,
+}
+
+impl<R: Read> LineReader<R> {
+    fn new(reader: R) -> Self {
+        LineReader::with_sizes(reader, CHUNK_SIZE, MAX_LINE_LENGTH, LONG_LINE_OVERLAP)
+    }
+
+    fn with_sizes(reader: R, chunk_size: usize, max_line: usize, overlap: usize) -> Self {
+        assert!(chunk_size > 0 && overlap < max_line);
+
+        LineReader {
+            reader,
+            buffer: Vec::new(),
+            start: 0,
+            end: 0,
+            eof: false,
+            offset: 0,
+            line: 0,
+            line_offset: 0,
+            cut: false,
+            chunk_size,
+            max_line,
+            overlap,
+        }
+    }
+
+    /// Reads the next chunk behind what's buffered. Returns false at the end
+    /// of the file.
+    fn fill(&mut self) -> io::Result<bool> {
+        // move what's left of the buffer to the front, to keep it short
+        self.buffer.copy_within(self.start..self.end, 0);
+        self.end -= self.start;
+        self.start = 0;
+        self.buffer.resize(self.end + self.chunk_size, 0);
+
+        let n = loop {
+            match self.reader.read(&mut self.buffer[self.end..]) {
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                result => break result?,
+            }
+        };
+
+        self.end += n;
+        self.eof = n == 0;
+        Ok(!self.eof)
+    }
+
+    /// Whether the file looks binary, i.e. its first chunk contains a NUL
+    /// byte, like grep decides it.
+    fn is_binary(&mut self) -> io::Result<bool> {
+        if self.start == self.end && !self.eof {
+            self.fill()?;
+        }
+
+        Ok(self.buffer[self.start..self.end].contains(&0))
+    }
+
+    /// The next line without its newline, or a piece of a long line.
+    fn next_line(&mut self) -> io::Result<Option<&[u8]>> {
+        let mut scanned = 0;
+
+        loop {
+            let (start, end) = (self.start, self.end);
+
+            if let Some(i) = self.buffer[start + scanned..end].iter().position(|&b| b == b'\n') {
+                let newline = start + scanned + i;
+                return Ok(Some(self.take(newline - start, newline + 1, false)));
+            }
+            scanned = end - start;
+
+            if scanned >= self.max_line {
+                return Ok(Some(self.take(self.max_line, start + self.max_line - self.overlap, true)));
+            }
+
+            if self.eof || !self.fill()? {
+                if scanned == 0 {
+                    return Ok(None);
+                }
+                // the last line has no newline
+                let start = self.start;
+                return Ok(Some(self.take(scanned, start + scanned, false)));
+            }
+        }
+    }
+
+    /// Hands out the `len` bytes at the start of the buffer as the next line
+    /// and continues reading at `next`.
+    fn take(&mut self, len: usize, next: usize, cut: bool) -> &[u8] {
+        let start = self.start;
+
+        // a piece following a cut is still the same line
+        if !self.cut {
+            self.line += 1;
+        }
+        self.cut = cut;
+        self.line_offset = self.offset;
+        self.offset += (next - start) as u64;
+        self.start = next;
+
+        &self.buffer[start..start + len]
+    }
+
+    /// The number of the line `next_line` returned last, counting from 1.
+    fn line_number(&self) -> u64 {
+        self.line
+    }
+
+    /// The byte offset of the line `next_line` returned last.
+    fn line_offset(&self) -> u64 {
+        self.line_offset
+    }
+
+    /// Whether the line `next_line` returned last was cut, so the next piece
+    /// is still part of it.
+    fn is_cut(&self) -> bool {
+        self.cut
+    }
+}
+
+/// Work a crew can do. Processing a work item can turn up more work items,
+/// which the crew hands out to its workers as well, and results.
+trait WorkItem: Send + Sized + 'static {
+    type Result: Send + 'static;
+
+    fn process(self, emit: &mut Emitter<Self>);
+}
+
+/// Collects the work items and results that processing a work item turns up.
+/// If the job has a sink, the results are sent there right away instead.
+struct Emitter<W: WorkItem> {
+    thread_index: usize,
+    work: Vec<W>,
+    results: Vec<W::Result>,
+    sink: Option<Sender<W::Result>>,
 }
 
-impl WorkItem {
-    fn new(p: PathBuf, s: String) -> Self {
-        WorkItem { path: p, search: s }
+impl<W: WorkItem> Emitter<W> {
+    /// The worker processing the work item.
+    fn thread_index(&self) -> usize {
+        self.thread_index
+    }
+
+    fn work(&mut self, w: W) {
+        self.work.push(w);
+    }
+
+    fn result(&mut self, r: W::Result) {
+        match self.sink {
+            // nobody listening anymore is no reason to stop working
+            Some(ref sink) => {
+                let _ = sink.send(r);
+            },
+            None => self.results.push(r),
+        }
     }
 }
 
-struct Crew {
-    work: Mutex<(Vec<WorkItem>, usize)>,
+/// What the crew turned up starting from one work item, see `Crew::start`.
+struct Report<R> {
+    results: Vec<R>,
+    // work items whose processing panicked
+    panicked: usize,
+}
+
+/// The work items waiting for a worker, and how many work items of the
+/// current job are still waiting or being processed. The job is `busy` until
+/// its results were collected, or, if it has a `sink`, sent there as soon as
+/// they turn up. Once `quit`, the workers exit as soon as no items are
+/// waiting.
+struct Queue<W: WorkItem> {
+    items: Vec<W>,
+    count: usize,
+    busy: bool,
+    results: Vec<W::Result>,
+    sink: Option<Sender<W::Result>>,
+    panicked: usize,
+    quit: bool,
+}
+
+struct CrewState<W: WorkItem> {
+    work: Mutex<Queue<W>>,
     go: Condvar,
     done: Condvar,
 }
 
-impl Crew {
-    fn new() -> Self {
-        Crew {
-            work: Mutex::new((Vec::new(), 0)),
+const CREW_SIZE: usize = 4;
+
+/// A crew of worker threads that runs one job after another, until it's
+/// shut down.
+struct Crew<W: WorkItem> {
+    state: Arc<CrewState<W>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl<W: WorkItem> Crew<W> {
+    fn new(crew_size: usize) -> Self {
+        let state = Arc::new(CrewState {
+            work: Mutex::new(Queue {
+                items: Vec::new(),
+                count: 0,
+                busy: false,
+                results: Vec::new(),
+                sink: None,
+                panicked: 0,
+                quit: false,
+            }),
             go: Condvar::new(),
             done: Condvar::new(),
-        }
+        });
+
+        let workers = (0..crew_size).map(|i| {
+            let state = state.clone();
+
+            thread::spawn(move || {
+                worker_routine(state, i);
+            })
+        }).collect();
+
+        Crew { state, workers }
+    }
+
+    /// Processes `w` and all work items it turns up. This blocks until the
+    /// crew is finished, and returns the results of this job only.
+    fn start(&self, w: W) -> Report<W::Result> {
+        self.run(w, None)
     }
 
-    fn start(&self, w: WorkItem) {        
-        match self.work.lock() {
-            Err(e) => panic!(format!("Error trying to lock mutex in order to start crew: {}", e)),
+    /// Like `start`, but sends the results to `sink` as they turn up
+    /// instead. Returns how many work items panicked.
+    fn stream(&self, w: W, sink: Sender<W::Result>) -> usize {
+        self.run(w, Some(sink)).panicked
+    }
+
+    fn run(&self, w: W, sink: Option<Sender<W::Result>>) -> Report<W::Result> {
+        let state = &self.state;
+
+        match state.work.lock() {
+            Err(e) => panic!("Error trying to lock mutex in order to start crew: {}", e),
             Ok(mut guard) => {
                 // wait for crew to finish with the old work
-                while guard.1 > 0 {
-                    guard = self.done.wait(guard).unwrap();
+                while guard.busy {
+                    guard = state.done.wait(guard).unwrap();
                 }
 
-                guard.0.push(w);
-                guard.1 += 1;
-                self.go.notify_one();
+                guard.items.push(w);
+                guard.count += 1;
+                guard.busy = true;
+                guard.sink = sink;
+                state.go.notify_one();
 
                 // wait for the crew to finish with the new work
-                while guard.1 > 0 {
-                    guard = self.done.wait(guard).unwrap();
+                while guard.count > 0 {
+                    guard = state.done.wait(guard).unwrap();
                 }
+
+                let report = Report {
+                    results: std::mem::take(&mut guard.results),
+                    panicked: std::mem::replace(&mut guard.panicked, 0),
+                };
+
+                // let the next job in
+                guard.sink = None;
+                guard.busy = false;
+                state.done.notify_all();
+
+                report
             },
         }
     }
-}
 
-fn start_crew_work(w: WorkItem, crew_size: usize) {
-    let crew = Arc::new(Crew::new());
-    let messages = Arc::new(Mutex::new(Vec::new()));
-
-    for i in 0..crew_size {
-        let crew = crew.clone();
-        let messages = messages.clone();
-        
-        thread::spawn(move || {
-            worker_routine(crew, messages, i);
-        });
+    /// Lets the workers finish and waits for them to exit.
+    fn shutdown(mut self) {
+        self.quit();
     }
 
-    // start the crew with the WorkItem. this blocks until the crew is finished
-    crew.start(w);
+    fn quit(&mut self) {
+        match self.state.work.lock() {
+            Err(e) => panic!("Error trying to lock mutex in order to shut down crew: {}", e),
+            Ok(mut guard) => {
+                guard.quit = true;
+                self.state.go.notify_all();
+            },
+        }
 
-    // print results
-    match messages.lock() {
-        Err(e) => panic!(format!("Main thread tried to lock message-queue: {}", e)),
-        Ok(messages) => {
-            for m in messages.iter() {
-                println!("{}", m);
+        for worker in self.workers.drain(..) {
+            if worker.join().is_err() {
+                panic!("A crew worker panicked.");
             }
-        },
-    };
+        }
+    }
+}
+
+impl<W: WorkItem> Drop for Crew<W> {
+    fn drop(&mut self) {
+        if !thread::panicking() {
+            self.quit();
+        }
+    }
 }
 
-fn worker_routine(crew: Arc<Crew>,
-                  mut messages: Messages,
-                  thread_index: usize)
-{
-    // keep doing work until all is finished
+fn worker_routine<W: WorkItem>(crew: Arc<CrewState<W>>, thread_index: usize) {
+    // keep doing work until the crew is shut down
     loop {
-        let workitem = match crew.work.lock() {
-            Err(e) => panic!(format!("Thread {} tried to lock crew mutex at the start: {}",
-                                     thread_index,
-                                     e)),
+        let (workitem, sink) = match crew.work.lock() {
+            Err(e) => panic!("Thread {} tried to lock crew mutex at the start: {}",
+                             thread_index,
+                             e),
             Ok(mut work) => {
-                while work.0.len() == 0 {
+                while work.items.is_empty() && !work.quit {
                     work = crew.go.wait(work).unwrap();
                 }
 
-                // we know there is an element in the work vector
-                let workitem = work.0.pop().unwrap();
-                workitem
+                match work.items.pop() {
+                    None => break,
+                    Some(workitem) => (workitem, work.sink.clone()),
+                }
             },
         };
 
-        messages = process_work_item(workitem, messages, thread_index, crew.clone());
+        let mut emit = Emitter { thread_index, work: Vec::new(), results: Vec::new(), sink };
+
+        // whatever happens to the work item, it has to be counted as done,
+        // or the crew would wait for it forever
+        let processed = panic::catch_unwind(AssertUnwindSafe(|| {
+            workitem.process(&mut emit);
+        }));
 
-        // correct work count. if we reached zero, then we're done
+        // hand out the new work and correct work count. if we reached zero,
+        // the job is done
         match crew.work.lock() {
-            Err(e) => panic!(format!("Thread {} tried to lock crew mutex after processing: {}",
-                                     thread_index,
-                                     e)),
+            Err(e) => panic!("Thread {} tried to lock crew mutex after processing: {}",
+                             thread_index,
+                             e),
             Ok(mut work) => {
-                work.1 -= 1;
-                if work.1 == 0 {
+                if processed.is_err() {
+                    work.panicked += 1;
+                }
+                if !emit.work.is_empty() {
+                    work.count += emit.work.len();
+                    work.items.append(&mut emit.work);
+                    crew.go.notify_all();
+                }
+                work.results.append(&mut emit.results);
+
+                work.count -= 1;
+                if work.count == 0 {
                     crew.done.notify_all();
-                    break;
                 }
             },
         }
     }
 }
 
-fn process_work_item(w: WorkItem,
-                     messages: Messages,
-                     thread_index: usize,
-                     crew: Arc<Crew>) -> Messages
-{
-    let mut thread_local_messages = Vec::with_capacity(16);
-
-    let file_type = match symlink_metadata(&w.path) {
-        Err(e) => panic!(format!("Thread {} tried to query metadata about file {:?}: {}",
-                                 thread_index,
-                                 &w.path,
-                                 e)),
-        Ok(m) => m,
-    }.file_type();
-
-    if file_type.is_symlink() {
-        thread_local_messages.push(format!("Thread {} found symlink {:?}, not processing.",
-                                           thread_index,
-                                           &w.path));
-    } else if file_type.is_dir() {
-        let dir_entries = match read_dir(&w.path) {
-            Err(e) => panic!(format!("Thread {} unable to list entries in directory {:?}: {}",
-                                     thread_index,
-                                     &w.path,
-                                     e)),
-            Ok(e) => e,
+/// What searching a path turned up.
+enum Found {
+    // a message about `path` from the thread that searched it
+    Message { path: PathBuf, thread: usize, text: String },
+    Error(PathError),
+}
+
+impl Found {
+    fn path(&self) -> &Path {
+        match *self {
+            Found::Message { ref path, .. } => path,
+            Found::Error(ref e) => &e.path,
+        }
+    }
+}
+
+/// A path to search, and everything below it.
+struct SearchItem {
+    path: PathBuf,
+    config: Arc<Config>,
+    // the (device, inode) pairs of the directories this search has entered,
+    // so following symlinks can't lead it around in circles
+    visited: Arc<Mutex<HashSet<(u64, u64)>>>,
+    // the device the search started on, unknown for the path it started with
+    device: Option<u64>,
+    // how many directories below the path the search started with
+    depth: usize,
+    ignores: Option<Arc<Ignores>>,
+}
+
+impl SearchItem {
+    fn new(p: PathBuf, c: Arc<Config>) -> Self {
+        SearchItem {
+            path: p,
+            config: c,
+            visited: Arc::new(Mutex::new(HashSet::new())),
+            device: None,
+            depth: 0,
+            ignores: None,
+        }
+    }
+
+    fn child(&self, p: PathBuf, device: u64, ignores: &Option<Arc<Ignores>>) -> Self {
+        SearchItem {
+            path: p,
+            config: self.config.clone(),
+            visited: self.visited.clone(),
+            device: Some(device),
+            depth: self.depth + 1,
+            ignores: ignores.clone(),
+        }
+    }
+
+    fn message(&self, thread: usize, text: String) -> Found {
+        Found::Message { path: self.path.clone(), thread, text }
+    }
+
+    /// Whether a directory entry is searched, decided before it becomes a
+    /// work item so that nothing below a skipped directory costs anything.
+    fn keeps(&self, entry: &DirEntry, ignores: &Option<Arc<Ignores>>) -> bool {
+        let path = entry.path();
+        let is_dir = match entry.file_type() {
+            Err(_) => false,
+            Ok(ref t) if t.is_symlink() && self.config.follow => path.is_dir(),
+            Ok(t) => t.is_dir(),
         };
 
-        let new_work_items = dir_entries.map(|result| {
-            let entry = match result {
-                Err(e) => panic!(format!("Thread {} unable to read directory entry: {}",
-                                         thread_index,
-                                         e)),
+        // a directory as deep as allowed wouldn't be read anyway
+        if is_dir && self.config.filter.max_depth.is_some_and(|max| self.depth + 1 >= max) {
+            return false;
+        }
+
+        self.config.filter.keeps(&path, is_dir, ignores.as_deref())
+    }
+
+    /// Marks the directory as entered, returns false if it had been already.
+    fn enter(&self, device: u64, inode: u64) -> bool {
+        match self.visited.lock() {
+            Err(e) => panic!("Error trying to lock visited directories: {}", e),
+            Ok(mut visited) => visited.insert((device, inode)),
+        }
+    }
+
+    fn search(self, emit: &mut Emitter<Self>) {
+        let thread_index = emit.thread_index();
+
+        // the file may be gone by now, or a symlink may point nowhere
+        let found = if self.config.follow {
+            metadata(&self.path)
+        } else {
+            symlink_metadata(&self.path)
+        };
+        let meta = match found {
+            Err(e) => {
+                emit.result(Found::Error(PathError { path: self.path, error: e }));
+                return;
+            },
+            Ok(m) => m,
+        };
+        let file_type = meta.file_type();
+        let device = self.device.unwrap_or_else(|| meta.dev());
+
+        if self.config.one_file_system && meta.dev() != device {
+            emit.result(self.message(thread_index, format!("found {:?} on another file system, not processing.",
+                                                           &self.path)));
+        } else if file_type.is_symlink() {
+            emit.result(self.message(thread_index, format!("found symlink {:?}, not processing.",
+                                                           &self.path)));
+        } else if file_type.is_dir() {
+            // without following symlinks, every directory is only reached once
+            if self.config.follow && !self.enter(meta.dev(), meta.ino()) {
+                emit.result(self.message(thread_index, format!("found {:?} again, not processing.",
+                                                               &self.path)));
+                return;
+            }
+
+            // only the path the search started with can be too deep here, the
+            // others were dropped by `keeps`
+            let filter = &self.config.filter;
+            if filter.max_depth.is_some_and(|max| self.depth >= max) {
+                return;
+            }
+
+            let dir_entries = match read_dir(&self.path) {
+                Err(e) => {
+                    emit.result(Found::Error(PathError { path: self.path, error: e }));
+                    return;
+                },
                 Ok(e) => e,
             };
-            WorkItem::new(entry.path(), w.search.clone())
-        }).collect::<Vec<_>>();
 
-        match crew.work.lock() {
-            Err(e) => panic!(format!("Thread {} unable to lock mutex to add new work items: {}",
-                                     thread_index,
-                                     e)),
-            Ok(mut work) => {
-                work.1 += new_work_items.len();
-                work.0.extend(new_work_items);
-                crew.go.notify_all();
-            },
+            let ignores = if filter.ignore_files {
+                match Ignores::read(&self.path, self.ignores.clone()) {
+                    Err(e) => {
+                        emit.result(Found::Error(e));
+                        self.ignores.clone()
+                    },
+                    Ok(i) => i,
+                }
+            } else {
+                None
+            };
+
+            for result in dir_entries {
+                match result {
+                    Err(e) => emit.result(Found::Error(PathError { path: self.path.clone(), error: e })),
+                    Ok(ref entry) if !self.keeps(entry, &ignores) => {},
+                    Ok(entry) => emit.work(self.child(entry.path(), device, &ignores)),
+                }
+            }
+        } else if file_type.is_file() {
+            if self.config.filter.max_file_size.is_some_and(|max| meta.len() > max) {
+                return;
+            }
+
+            let file = match File::open(&self.path) {
+                Err(e) => {
+                    emit.result(Found::Error(PathError { path: self.path, error: e }));
+                    return;
+                },
+                Ok(f) => f,
+            };
+
+            let mut lines = LineReader::new(file);
+            let config = &self.config;
+            let context = config.before > 0 || config.after > 0;
+
+            // a file with many matches is reported in several messages, so
+            // that they don't all have to be kept until the end
+            let searched = match lines.is_binary() {
+                Ok(true) if !config.binary => {
+                    emit.result(self.message(thread_index, format!("found binary file {:?}, not processing.",
+                                                                   &self.path)));
+                    Ok(())
+                },
+                Ok(_) => config.search.find(&mut lines, config.before, config.after, |found| {
+                    let count = found.iter().filter(|line| line.matched).count();
+                    emit.result(self.message(thread_index, format!("found {} {} in {:?}:\n{}",
+                                                                   count,
+                                                                   if count == 1 { "match" } else { "matches" },
+                                                                   &self.path,
+                                                                   format_lines(&self.path, &found, context,
+                                                                                config.byte_offset))));
+                }),
+                Err(e) => Err(e),
+            };
+
+            if let Err(e) = searched {
+                emit.result(Found::Error(PathError { path: self.path.clone(), error: e }));
+            }
+        } else {
+            let message = format!("could not process file {:?} because it is {}.",
+                                  &self.path,
+                                  if file_type.is_block_device() {
+                                      "a block device"
+                                  } else if file_type.is_char_device() {
+                                      "a char device"
+                                  } else if file_type.is_fifo() {
+                                      "a fifo"
+                                  } else if file_type.is_socket() {
+                                      "a socket"
+                                  } else {
+                                      "unknown"
+                                  }
+            );
+
+            emit.result(self.message(thread_index, message));
         }
-    } else if file_type.is_file() {
-        let mut buffer = String::new();
-            
-        let mut file = match File::open(&w.path) {
-            Err(e) => panic!(format!("Thread {} tried to open file {:?}: {}",
-                                     thread_index,
-                                     &w.path,
-                                     e)),
-            Ok(f) => f,
-        };
+    }
+}
+
+impl WorkItem for SearchItem {
+    type Result = Found;
 
-        match file.read_to_string(&mut buffer) {
-            Err(e) => panic!(format!("Thread {} tried to read file {:?}: {}",
-                                     thread_index,
-                                     &w.path,
-                                     e)),
-            Ok(_) => {},
+    fn process(self, emit: &mut Emitter<Self>) {
+        let path = self.path.clone();
+        let thread_index = emit.thread_index();
+
+        // the crew would only count the panic, report which path it was
+        if panic::catch_unwind(AssertUnwindSafe(|| self.search(emit))).is_err() {
+            let error = io::Error::other(format!("thread {} panicked searching it", thread_index));
+            emit.result(Found::Error(PathError { path, error }));
         }
+    }
+}
+
+/// Prints what the crew finds while it is searching, from a thread of its
+/// own so that the output of the workers doesn't interleave. The paths it
+/// couldn't search are listed once the search is over. Returns false if
+/// there were any.
+fn printer(results: Receiver<Found>) -> bool {
+    let mut errors = Vec::new();
 
-        if buffer.contains(&w.search) {
-            thread_local_messages.push(format!("Thread {} found {:?} in {:?}",
-                                               thread_index,
-                                               &w.search,
-                                               &w.path));
+    for found in results {
+        match found {
+            Found::Message { thread, text, .. } => println!("Thread {} {}", thread, text),
+            Found::Error(e) => errors.push(e),
         }
-    } else {
-        let message = format!("Thread {} could not process file because it is {}.",
-                              thread_index,
-                              if file_type.is_block_device() {
-                                  "a block device"
-                              } else if file_type.is_char_device() {
-                                  "a char device"
-                              } else if file_type.is_fifo() {
-                                  "a fifo"
-                              } else if file_type.is_socket() {
-                                  "a socket"
-                              } else {
-                                  "unknown"
-                              }
-        );
-
-        thread_local_messages.push(message);
-    }
-    
-    match messages.lock() {
-        Err(e) => panic!(format!("Thread {} could not lock message mutex: {}",
-                                 thread_index,
-                                 e)),
-        Ok(mut m) => m.extend(thread_local_messages),
-    }
-
-    messages
+    }
+
+    print_errors(&errors)
+}
+
+/// Prints what a search found ordered by path, without the threads that
+/// found it, so that the output is the same on every run. Returns false if
+/// there were paths it couldn't search.
+fn print_sorted(mut results: Vec<Found>) -> bool {
+    let mut errors = Vec::new();
+
+    results.sort_by(|a, b| a.path().cmp(b.path()));
+
+    for found in results {
+        match found {
+            Found::Message { text, .. } => println!("{}", text),
+            Found::Error(e) => errors.push(e),
+        }
+    }
+
+    print_errors(&errors)
+}
+
+fn print_errors(errors: &[PathError]) -> bool {
+    for e in errors.iter() {
+        writeln!(&mut std::io::stderr(), "crew: {}", e).unwrap();
+    }
+
+    errors.is_empty()
 }
 
 fn main() {
+    let mut patterns = Vec::new();
+    let mut literal = false;
+    let mut ignore_case = false;
+    let mut all = false;
+    let mut binary = false;
+    let mut before = 0;
+    let mut after = 0;
+    let mut byte_offset = false;
+    let mut follow = false;
+    let mut one_file_system = false;
+    let mut include = Vec::new();
+    let mut exclude = Vec::new();
+    let mut filter = Filter::default();
+    let mut sorted = false;
+    let mut positional = Vec::new();
+
     let mut args = std::env::args().skip(1);
-    let search = match args.next() {
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-e" | "--regexp" => match args.next() {
+                None => abort_with_usage_message(),
+                Some(p) => patterns.push(p),
+            },
+            "-F" | "--fixed-strings" => literal = true,
+            "-i" | "--ignore-case" => ignore_case = true,
+            "--any" => all = false,
+            "--all" => all = true,
+            "-a" | "--text" => binary = true,
+            "-A" | "--after-context" => after = number(&mut args),
+            "-B" | "--before-context" => before = number(&mut args),
+            "-C" | "--context" => {
+                after = number(&mut args);
+                before = after;
+            },
+            "-b" | "--byte-offset" => byte_offset = true,
+            "-L" | "--follow" => follow = true,
+            "--one-file-system" => one_file_system = true,
+            "--include" | "--exclude" => match args.next() {
+                None => abort_with_usage_message(),
+                Some(g) => if arg == "--include" { include.push(g) } else { exclude.push(g) },
+            },
+            "--skip-hidden" => filter.skip_hidden = true,
+            "--ignore-files" => filter.ignore_files = true,
+            "--max-depth" => filter.max_depth = Some(number(&mut args)),
+            "--max-filesize" => filter.max_file_size = Some(file_size(&mut args)),
+            "--sorted" => sorted = true,
+            _ if arg.starts_with('-') => abort_with_usage_message(),
+            _ => positional.push(arg),
+        }
+    }
+
+    // without -e, the first argument is the pattern
+    if patterns.is_empty() && !positional.is_empty() {
+        patterns.push(positional.remove(0));
+    }
+    if positional.is_empty() {
+        abort_with_usage_message();
+    }
+
+    let search = match Search::new(&patterns, literal, ignore_case, all) {
+        Err(e) => {
+            writeln!(&mut std::io::stderr(), "crew: {}", e).unwrap();
+            std::process::exit(1)
+        },
+        Ok(s) => s,
+    };
+
+    match Filter::globs(&include, &exclude) {
+        Err(e) => {
+            writeln!(&mut std::io::stderr(), "crew: {}", e).unwrap();
+            std::process::exit(1)
+        },
+        Ok((i, e)) => {
+            filter.include = i;
+            filter.exclude = e;
+        },
+    }
+
+    let config = Arc::new(Config {
+        search,
+        binary,
+        before,
+        after,
+        byte_offset,
+        follow,
+        one_file_system,
+        filter,
+    });
+
+    // every path is a search of its own, done by the same crew. a search
+    // reports the paths it panicked on itself, so the crew never counts any
+    let crew = Crew::new(CREW_SIZE);
+    let mut ok = true;
+    if sorted {
+        for path in positional {
+            let report = crew.start(SearchItem::new(PathBuf::from(path), config.clone()));
+            ok &= print_sorted(report.results);
+        }
+    } else {
+        let (sink, results) = channel();
+        let printer = thread::spawn(move || printer(results));
+
+        for path in positional {
+            crew.stream(SearchItem::new(PathBuf::from(path), config.clone()), sink.clone());
+        }
+
+        // the printer is done once the last sender is gone
+        drop(sink);
+        ok &= printer.join().unwrap_or(false);
+    }
+    crew.shutdown();
+
+    if !ok {
+        std::process::exit(2);
+    }
+}
+
+fn number<A: Iterator<Item = String>>(args: &mut A) -> usize {
+    match args.next().map(|n| n.parse()) {
+        Some(Ok(n)) => n,
+        _ => abort_with_usage_message(),
+    }
+}
+
+/// A number of bytes, optionally in K, M or G.
+fn file_size<A: Iterator<Item = String>>(args: &mut A) -> u64 {
+    let size = match args.next() {
         None => abort_with_usage_message(),
         Some(s) => s,
     };
-    let path = match args.next() {
-        None => abort_with_usage_message(),
-        Some(s) => PathBuf::from(s),
+    let (digits, unit) = match size.char_indices().last() {
+        Some((i, 'K')) => (&size[..i], 1 << 10),
+        Some((i, 'M')) => (&size[..i], 1 << 20),
+        Some((i, 'G')) => (&size[..i], 1 << 30),
+        _ => (&size[..], 1),
     };
 
-    start_crew_work(WorkItem::new(path, search), 4);
+    match digits.parse::<u64>().ok().and_then(|n| n.checked_mul(unit)) {
+        Some(n) => n,
+        None => abort_with_usage_message(),
+    }
 }
 
 fn abort_with_usage_message() -> ! {
-    writeln!(&mut std::io::stderr(), "usage: crew search path").unwrap();
+    writeln!(&mut std::io::stderr(), "usage: crew [-i] [-F] [-a] [-b] [-A N] [-B N] [-C N] [-L] [--one-file-system] [--any | --all] [-e pattern]... [pattern] path...").unwrap();
+    writeln!(&mut std::io::stderr(), "       [--include glob]... [--exclude glob]... [--skip-hidden] [--ignore-files] [--max-depth N] [--max-filesize N[K|M|G]] [--sorted]").unwrap();
     std::process::exit(1)
 }
+
+#[cfg(test)]
+mod test {
+    use super::{Search, LineReader, Line, Config, Filter, Crew, WorkItem, Emitter, SearchItem, Found, Report,
+                format_lines, BATCH_LINES};
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+    use std::sync::mpsc::channel;
+    use std::thread;
+    use std::fs;
+    use std::os::unix::fs::symlink;
+    use std::io::Read;
+
+    fn search(patterns: &[&str], literal: bool, ignore_case: bool, all: bool) -> Search {
+        let patterns = patterns.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+        Search::new(&patterns, literal, ignore_case, all).unwrap()
+    }
+
+    fn find<R: Read>(search: &Search, lines: &mut LineReader<R>, before: usize, after: usize) -> Vec<Line> {
+        let mut found = Vec::new();
+        search.find(lines, before, after, |batch| found.extend(batch)).unwrap();
+        found
+    }
+
+    fn is_match(search: &Search, text: &str) -> bool {
+        !find(search, &mut LineReader::new(text.as_bytes()), 0, 0).is_empty()
+    }
+
+    fn grep(search: &Search, text: &str, before: usize, after: usize) -> String {
+        let found = find(search, &mut LineReader::with_sizes(text.as_bytes(), 4, 64, 8), before, after);
+        format_lines(Path::new("f"), &found, before > 0 || after > 0, true)
+    }
+
+    fn lines(text: &[u8], chunk_size: usize, max_line: usize, overlap: usize) -> Vec<String> {
+        let mut reader = LineReader::with_sizes(text, chunk_size, max_line, overlap);
+        let mut lines = Vec::new();
+
+        while let Some(line) = reader.next_line().unwrap() {
+            lines.push(String::from_utf8_lossy(line).into_owned());
+        }
+
+        lines
+    }
+
+    #[test]
+    fn any_or_all_patterns() {
+        let any = search(&["mutex", "cond(var)?"], false, false, false);
+        assert!(is_match(&any, "a Condvar and a condition"));
+        assert!(!is_match(&any, "a Mutex"));
+
+        let all = search(&["mutex", "cond(var)?"], false, false, true);
+        assert!(!is_match(&all, "a condition"));
+        assert!(is_match(&all, "a condition\nand a mutex"));
+        assert_eq!(all.to_string(), r#""mutex" and "cond(var)?""#);
+    }
+
+    #[test]
+    fn case_insensitive_and_literal_patterns() {
+        assert!(is_match(&search(&["mutex"], false, true, false), "a Mutex"));
+        assert!(is_match(&search(&["a.b"], false, false, false), "axb"));
+        assert!(!is_match(&search(&["a.b"], true, false, false), "axb"));
+        assert!(is_match(&search(&["a.b"], true, false, false), "a.b"));
+        assert!(Search::new(&["(".to_string()], false, false, false).is_err());
+    }
+
+    #[test]
+    fn lines_across_chunks() {
+        let text = b"first line\nsecond\n\nlast without newline";
+
+        for chunk_size in 1..8 {
+            assert_eq!(lines(text, chunk_size, 64, 4),
+                       vec!["first line", "second", "", "last without newline"]);
+        }
+        assert!(lines(b"", 4, 64, 4).is_empty());
+        assert_eq!(lines(b"one\n", 4, 64, 4), vec!["one"]);
+    }
+
+    #[test]
+    fn long_lines_are_cut_with_overlap() {
+        assert_eq!(lines(b"abcdefghij\nxy", 3, 4, 1), vec!["abcd", "defg", "ghij", "xy"]);
+
+        // a match across a cut is found in the overlap
+        let found = find(&search(&["efgh"], false, false, false),
+                         &mut LineReader::with_sizes(&b"abcdefghijkl"[..], 2, 6, 4), 0, 0);
+        assert_eq!(found.len(), 1);
+        assert_eq!((found[0].number, found[0].offset), (1, 2));
+    }
+
+    #[test]
+    fn long_lines_with_context() {
+        let text = "a\nxxxxxxxxxxxxneedle\nb\n";
+        let grep_long = |patterns: &[&str], before, after| {
+            let found = find(&search(patterns, false, false, false),
+                             &mut LineReader::with_sizes(text.as_bytes(), 4, 8, 6), before, after);
+            format_lines(Path::new("f"), &found, before > 0 || after > 0, true)
+        };
+
+        // the line is reported once, with the piece that matched
+        assert_eq!(grep_long(&["needle"], 1, 0), "f-1-0-a\nf:2:12:xxneedle");
+        assert_eq!(grep_long(&["needle"], 0, 1), "f:2:12:xxneedle\nf-3-21-b");
+        assert_eq!(grep_long(&["^a$", "needle"], 0, 1), "f:1:0:a\nf:2:12:xxneedle\nf-3-21-b");
+        assert_eq!(grep_long(&["^x"], 1, 1), "f-1-0-a\nf:2:2:xxxxxxxx\nf-3-21-b");
+    }
+
+    #[test]
+    fn binary_files() {
+        assert!(LineReader::new(&b"text\0more"[..]).is_binary().unwrap());
+        assert!(!LineReader::new(&b"text\nmore"[..]).is_binary().unwrap());
+        assert!(!LineReader::new(&b""[..]).is_binary().unwrap());
+
+        // a file can be searched after checking
+        let mut reader = LineReader::new(&b"\xff\xfe\0mutex"[..]);
+        assert!(reader.is_binary().unwrap());
+        assert_eq!(find(&search(&["mutex"], false, false, false), &mut reader, 0, 0).len(), 1);
+    }
+
+    #[test]
+    fn many_matches_are_reported_in_batches() {
+        let batches = |patterns: &[&str], all, text: &str| {
+            let mut batches = Vec::new();
+            search(patterns, false, false, all)
+                .find(&mut LineReader::new(text.as_bytes()), 0, 0, |batch| batches.push(batch.len()))
+                .unwrap();
+            batches
+        };
+
+        let text = "match\n".repeat(2 * BATCH_LINES + 1);
+        assert_eq!(batches(&["match"], false, &text), vec![BATCH_LINES, BATCH_LINES, 1]);
+
+        // with all, the lines are kept until the last pattern matched
+        let text = text + "other\n";
+        assert_eq!(batches(&["match", "other"], true, &text), vec![2 * BATCH_LINES + 1, 1]);
+        assert!(batches(&["match", "missing"], true, &text).is_empty());
+    }
+
+    #[test]
+    fn matches_with_context() {
+        let text = "a\nmatch 1\nb\nc\nd\ne\nmatch 2\nmatch 3\nf\ng";
+        let s = search(&["match"], false, false, false);
+
+        assert_eq!(grep(&s, text, 0, 0), "f:2:2:match 1\nf:7:18:match 2\nf:8:26:match 3");
+        assert_eq!(grep(&s, text, 1, 1), "f-1-0-a\nf:2:2:match 1\nf-3-10-b\n--\n\
+                                          f-6-16-e\nf:7:18:match 2\nf:8:26:match 3\nf-9-34-f");
+        // context that touches doesn't get a separator
+        assert_eq!(grep(&s, text, 2, 2), "f-1-0-a\nf:2:2:match 1\nf-3-10-b\nf-4-12-c\n\
+                                          f-5-14-d\nf-6-16-e\nf:7:18:match 2\nf:8:26:match 3\n\
+                                          f-9-34-f\nf-10-36-g");
+    }
+
+    fn config(patterns: &[&str]) -> Config {
+        Config {
+            search: search(patterns, false, false, false),
+            binary: false,
+            before: 0,
+            after: 0,
+            byte_offset: false,
+            follow: false,
+            one_file_system: false,
+            filter: Filter::default(),
+        }
+    }
+
+    // an empty directory to play in, removed again when dropped
+    struct Scratch(PathBuf);
+
+    impl Scratch {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("crew-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            Scratch(dir)
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn messages(report: &Report<Found>) -> Vec<&str> {
+        report.results.iter().filter_map(|found| match *found {
+            Found::Message { ref text, .. } => Some(&text[..]),
+            Found::Error(_) => None,
+        }).collect()
+    }
+
+    fn error_paths(report: &Report<Found>) -> Vec<&Path> {
+        report.results.iter().filter_map(|found| match *found {
+            Found::Message { .. } => None,
+            Found::Error(ref e) => Some(e.path.as_path()),
+        }).collect()
+    }
+
+    #[test]
+    fn missing_paths_are_reported_not_fatal() {
+        let crew = Crew::new(2);
+        let report = crew.start(SearchItem::new(PathBuf::from("/nonexistent/crew/path"), Arc::new(config(&["x"]))));
+
+        assert!(messages(&report).is_empty());
+        assert_eq!(error_paths(&report), vec![Path::new("/nonexistent/crew/path")]);
+    }
+
+    #[test]
+    fn crew_runs_one_search_after_another() {
+        let scratch = Scratch::new("jobs");
+        let root = &scratch.0;
+        fs::create_dir(root.join("sub")).unwrap();
+        fs::write(root.join("a.txt"), "hay\nneedle\n").unwrap();
+        fs::write(root.join("sub/b.txt"), "needle\nhay\n").unwrap();
+        fs::write(root.join("sub/c.txt"), "hay\n").unwrap();
+
+        let crew = Crew::new(3);
+
+        for _ in 0..3 {
+            let report = crew.start(SearchItem::new(root.join("a.txt"), Arc::new(config(&["^needle"]))));
+            assert_eq!(messages(&report).len(), 1);
+            assert!(messages(&report)[0].contains("found 1 match"));
+
+            let report = crew.start(SearchItem::new(root.clone(), Arc::new(config(&["^needle"]))));
+            assert_eq!(matched(&report, root), vec!["a.txt", "sub/b.txt"]);
+            assert!(error_paths(&report).is_empty());
+        }
+
+        crew.shutdown();
+    }
+
+    #[test]
+    fn symlinks_are_followed_around_loops() {
+        let scratch = Scratch::new("symlinks");
+        let dir = scratch.0.join("dir");
+        fs::create_dir(&dir).unwrap();
+        fs::write(dir.join("file"), "needle\n").unwrap();
+        symlink(&scratch.0, dir.join("loop")).unwrap();
+        symlink(dir.join("file"), scratch.0.join("link")).unwrap();
+
+        let crew = Crew::new(2);
+
+        let report = crew.start(SearchItem::new(scratch.0.clone(), Arc::new(config(&["needle"]))));
+        let found = messages(&report);
+        assert_eq!(found.iter().filter(|m| m.contains("found 1 match")).count(), 1);
+        assert_eq!(found.iter().filter(|m| m.contains("found symlink")).count(), 2);
+
+        let follow = Config { follow: true, ..config(&["needle"]) };
+        let report = crew.start(SearchItem::new(scratch.0.clone(), Arc::new(follow)));
+        let found = messages(&report);
+        // the file itself and the link to it, but the loop is only taken once
+        assert_eq!(found.iter().filter(|m| m.contains("found 1 match")).count(), 2);
+        assert_eq!(found.iter().filter(|m| m.contains("again, not processing")).count(), 1);
+        assert!(error_paths(&report).is_empty());
+
+        // everything is on one file system
+        let one = Config { one_file_system: true, ..config(&["needle"]) };
+        let report = crew.start(SearchItem::new(scratch.0.clone(), Arc::new(one)));
+        assert!(!messages(&report).iter().any(|m| m.contains("another file system")));
+
+        crew.shutdown();
+    }
+
+    // the files a search found matches in, relative to `root`
+    fn matched(report: &Report<Found>, root: &Path) -> Vec<String> {
+        let mut paths: Vec<String> = messages(report).iter().filter_map(|m| {
+            let start = m.find(" in \"")? + 5;
+            let end = m.find("\":\n")?;
+            Some(Path::new(&m[start..end]).strip_prefix(root).unwrap().display().to_string())
+        }).collect();
+        paths.sort();
+        paths
+    }
+
+    #[test]
+    fn filters_prune_the_search() {
+        let scratch = Scratch::new("filters");
+        let root = &scratch.0;
+        for dir in &["build", "sub/deep", ".git"] {
+            fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        for file in &["a.txt", "a.log", "keep.log", ".hidden", "build/b.txt", "sub/c.rs", "sub/deep/d.txt", ".git/e"] {
+            fs::write(root.join(file), "needle\n").unwrap();
+        }
+        fs::write(root.join("big.txt"), format!("needle\n{}\n", "x".repeat(2000))).unwrap();
+        fs::write(root.join(".gitignore"), "# logs\n*.log\n!keep.log\n/build/\n").unwrap();
+        fs::write(root.join("sub/.ignore"), "deep/*.txt\n").unwrap();
+
+        let crew = Crew::new(2);
+        let search = |filter: Filter| {
+            let config = Config { filter, ..config(&["needle"]) };
+            matched(&crew.start(SearchItem::new(root.clone(), Arc::new(config))), root)
+        };
+
+        assert_eq!(search(Filter::default()).len(), 9);
+
+        assert_eq!(search(Filter { skip_hidden: true, ignore_files: true, ..Filter::default() }),
+                   vec!["a.txt", "big.txt", "keep.log", "sub/c.rs"]);
+
+        let (include, exclude) = Filter::globs(&["*.txt".to_string()], &["sub".to_string(), ".*".to_string()]).unwrap();
+        assert_eq!(search(Filter { include, exclude, ..Filter::default() }),
+                   vec!["a.txt", "big.txt", "build/b.txt"]);
+
+        assert_eq!(search(Filter { max_depth: Some(1), max_file_size: Some(100), skip_hidden: true, ..Filter::default() }),
+                   vec!["a.log", "a.txt", "keep.log"]);
+
+        // directories at the depth limit don't even become work items
+        let keeps = |max_depth, name: &str| {
+            let config = Config { filter: Filter { max_depth: Some(max_depth), ..Filter::default() }, ..config(&["needle"]) };
+            let item = SearchItem::new(root.clone(), Arc::new(config));
+            let entry = fs::read_dir(root).unwrap().map(|e| e.unwrap()).find(|e| e.file_name() == name).unwrap();
+            item.keeps(&entry, &None)
+        };
+        assert!(!keeps(1, "sub"));
+        assert!(keeps(1, "a.txt"));
+        assert!(keeps(2, "sub"));
+
+        crew.shutdown();
+    }
+
+    // the nodes of a complete binary tree of the given depth, numbered from 1
+    struct Node {
+        number: u32,
+        depth: u32,
+    }
+
+    impl WorkItem for Node {
+        type Result = u32;
+
+        fn process(self, emit: &mut Emitter<Self>) {
+            if self.number == 13 {
+                panic!("unlucky node");
+            }
+            if self.depth > 0 {
+                emit.work(Node { number: 2 * self.number, depth: self.depth - 1 });
+                emit.work(Node { number: 2 * self.number + 1, depth: self.depth - 1 });
+            }
+            emit.result(self.number);
+        }
+    }
+
+    #[test]
+    fn crew_expands_any_kind_of_work() {
+        let crew = Crew::new(4);
+
+        let mut report = crew.start(Node { number: 1, depth: 3 });
+        report.results.sort();
+        assert_eq!(report.results, (1..16).filter(|&n| n != 13).collect::<Vec<_>>());
+        assert_eq!(report.panicked, 1);
+
+        // a panic only spoils the job it happened in
+        let report = crew.start(Node { number: 1, depth: 1 });
+        assert_eq!(report.results.len(), 3);
+        assert_eq!(report.panicked, 0);
+
+        crew.shutdown();
+    }
+
+    #[test]
+    fn crew_streams_results_as_they_turn_up() {
+        let crew = Crew::new(4);
+        let (sink, results) = channel();
+        let collector = thread::spawn(move || results.iter().collect::<Vec<u32>>());
+
+        assert_eq!(crew.stream(Node { number: 1, depth: 3 }, sink.clone()), 1);
+        assert_eq!(crew.stream(Node { number: 1, depth: 0 }, sink), 0);
+
+        let mut streamed = collector.join().unwrap();
+        streamed.sort();
+        let mut expected: Vec<u32> = (1..16).filter(|&n| n != 13).collect();
+        expected.push(1);
+        expected.sort();
+        assert_eq!(streamed, expected);
+
+        // streaming leaves nothing behind for the next job to collect
+        assert_eq!(crew.start(Node { number: 1, depth: 0 }).results, vec![1]);
+
+        crew.shutdown();
+    }
+}
@@ -1,179 +1,1044 @@
+extern crate rand;
+
 use std::sync::{Mutex, Condvar, Arc};
-use std::thread;
-use std::ops::Deref;
-use std::io::Write;
+use std::thread::{self, JoinHandle};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use std::io::{Write, BufRead, BufReader};
+use std::fmt;
+use std::convert::Infallible;
+
+#[derive(Debug, PartialEq)]
+enum PipeError {
+    Closed,
+    Empty,
+    NotReady,
+    Timeout,
+    Stage { stage: usize, message: String },
+    Poisoned,
+}
 
-struct Stage {
+impl fmt::Display for PipeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            PipeError::Closed => write!(f, "Pipe is closed."),
+            PipeError::Empty => write!(f, "Pipe is empty."),
+            PipeError::NotReady => write!(f, "No result is ready yet."),
+            PipeError::Timeout => write!(f, "Timed out waiting for a result."),
+            PipeError::Stage { stage, ref message } => write!(f, "Stage {} failed: {}", stage, message),
+            PipeError::Poisoned => write!(f, "Pipe is poisoned, a stage panicked."),
+        }
+    }
+}
+
+impl std::error::Error for PipeError {}
+
+/// Items travel through the pipe as results, so a stage's error is carried
+/// along to `pipe_result` in place of the item it failed on.
+type Item<T> = Result<T, PipeError>;
+
+struct Stage<T> {
     avail: Condvar,
     ready: Condvar,
-    stage_data: Mutex<StageData>,
+    stage_data: Mutex<StageData<T>>,
 }
 
-impl Stage {
-    fn new(data: u64) -> Self {
+impl<T> Stage<T> {
+    fn new(capacity: usize) -> Self {
+        assert!(capacity > 0);
+
         Stage {
             avail: Condvar::new(),
             ready: Condvar::new(),
-            stage_data: Mutex::new(StageData { data: data, unprocessed: false }),
+            stage_data: Mutex::new(StageData {
+                data: VecDeque::with_capacity(capacity),
+                capacity,
+                taken: 0,
+                closed: false,
+                poisoned: false,
+            }),
+        }
+    }
+}
+
+/// The buffer a stage's items wait in until the stage processes them. Senders
+/// block on `ready` while `capacity` items are waiting, the stage blocks on
+/// `avail` while there are none. `taken` counts the items the stage's threads
+/// took out, which numbers them for an ordered fan-in. Once `closed`, no more
+/// items will arrive. A `poisoned` stage is closed and lost its items because
+/// a stage thread panicked somewhere in the pipe.
+struct StageData<T> {
+    data: VecDeque<Item<T>>,
+    capacity: usize,
+    taken: u64,
+    closed: bool,
+    poisoned: bool,
+}
+
+/// Where the threads of a replicated stage meet again. The last thread to
+/// finish closes the next stage, and if `ordered`, results are sent on in the
+/// order their items were taken from the input.
+struct FanIn {
+    state: Mutex<FanInState>,
+    turn: Condvar,
+    ordered: bool,
+}
+
+struct FanInState {
+    running: usize,
+    next_seq: u64,
+    poisoned: bool,
+}
+
+impl FanIn {
+    fn new(replicas: usize, ordered: bool) -> Self {
+        FanIn {
+            state: Mutex::new(FanInState { running: replicas, next_seq: 0, poisoned: false }),
+            turn: Condvar::new(),
+            ordered,
         }
     }
+
+    /// Sends `data` on to `next`, after all items taken before it if ordered.
+    fn send<T>(&self, next: &Stage<T>, seq: u64, data: Item<T>) -> Result<(), PipeError> {
+        if !self.ordered {
+            return send(next, data);
+        }
+
+        match self.state.lock() {
+            Err(e) => panic!("Error trying to lock fan-in mutex: {}", e),
+            Ok(mut state) => {
+                while state.next_seq != seq && !state.poisoned {
+                    state = self.turn.wait(state).unwrap();
+                }
+                // the thread whose turn it is panicked
+                if state.poisoned {
+                    return Err(PipeError::Poisoned);
+                }
+
+                let result = send(next, data);
+                state.next_seq += 1;
+                self.turn.notify_all();
+                result
+            },
+        }
+    }
+
+    /// Called by every thread of the stage on its way out.
+    fn leave<T>(&self, next: &Stage<T>) {
+        match self.state.lock() {
+            Err(e) => panic!("Error trying to lock fan-in mutex: {}", e),
+            Ok(mut state) => {
+                state.running -= 1;
+                if state.running == 0 {
+                    close(next);
+                }
+            },
+        }
+    }
+
+    /// Wakes up the threads waiting for a turn that will never come.
+    fn poison(&self) {
+        let mut state = match self.state.lock() {
+            Err(e) => e.into_inner(),
+            Ok(state) => state,
+        };
+
+        state.poisoned = true;
+        self.turn.notify_all();
+    }
+}
+
+/// What the threads of a stage spent their time on: waiting on `avail` for
+/// an item (starved), processing items, and waiting on the next stage's
+/// `ready` to pass them on (blocked), summed over all replicas.
+#[derive(Clone, Debug, Default)]
+struct StageStats {
+    replicas: usize,
+    items: u64,
+    starved: Duration,
+    processing: Duration,
+    blocked: Duration,
+}
+
+impl StageStats {
+    fn new(replicas: usize) -> Self {
+        StageStats { replicas, ..StageStats::default() }
+    }
+
+    fn record(&mut self, starved: Duration, processing: Duration, blocked: Duration) {
+        self.items += 1;
+        self.starved += starved;
+        self.processing += processing;
+        self.blocked += blocked;
+    }
+
+    /// Processing time per replica, the wall time the stage was busy for.
+    fn busy(&self) -> Duration {
+        self.processing / self.replicas as u32
+    }
+}
+
+/// The stats of every stage in a pipe, see `pipe_stats`.
+struct PipeStats {
+    stages: Vec<StageStats>,
+}
+
+impl PipeStats {
+    /// The stage that was busy for the longest, which holds up the others.
+    fn bottleneck(&self) -> Option<usize> {
+        (0..self.stages.len()).max_by_key(|&i| self.stages[i].busy())
+    }
 }
 
-struct StageData {
-    data: u64,
-    unprocessed: bool,
+impl fmt::Display for PipeStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        writeln!(f, "{:>6} {:>9} {:>10} {:>14} {:>14} {:>14}",
+                 "stage", "replicas", "items", "starved", "processing", "blocked")?;
+
+        for (i, stats) in self.stages.iter().enumerate() {
+            writeln!(f, "{:>6} {:>9} {:>10} {:>14} {:>14} {:>14}",
+                     i, stats.replicas, stats.items, format!("{:?}", stats.starved),
+                     format!("{:?}", stats.processing), format!("{:?}", stats.blocked))?;
+        }
+
+        if let Some(i) = self.bottleneck() {
+            writeln!(f, "bottleneck: stage {}", i)?;
+        }
+
+        Ok(())
+    }
 }
 
-struct Pipe {
-    stages: Vec<Stage>,
+struct Pipe<I, O> {
+    head: Arc<Stage<I>>,
+    tail: Arc<Stage<O>>,
     active_count: Mutex<usize>,
+    started: Condvar,
+    handles: Mutex<Vec<JoinHandle<()>>>,
+    stats: Vec<Arc<Mutex<StageStats>>>,
 }
 
-impl Pipe {
-    fn new(stages: usize) -> Self {
-        Pipe {
-            stages: (0..stages + 1).map(|_| Stage::new(0)).collect(),
-            active_count: Mutex::new(0),
+impl<I, O> Pipe<I, O> {
+    fn head(&self) -> &Stage<I> {
+        &self.head
+    }
+
+    fn tail(&self) -> &Stage<O> {
+        &self.tail
+    }
+}
+
+/// Builds a pipe stage by stage. Every stage is a function from the previous
+/// stage's item type to its own item type and gets a thread of its own once
+/// the pipe is built.
+struct PipeBuilder<I, O> {
+    head: Arc<Stage<I>>,
+    tail: Arc<Stage<O>>,
+    capacity: usize,
+    stats: Vec<Arc<Mutex<StageStats>>>,
+    workers: Vec<Box<dyn FnOnce() + Send>>,
+}
+
+impl<I: Send + 'static> PipeBuilder<I, I> {
+    /// A pipe whose stages each buffer up to `capacity` items, so a slow
+    /// stage doesn't immediately stall the ones before it. With a capacity of
+    /// 1 they hand over one item at a time, like the book's.
+    fn with_capacity(capacity: usize) -> Self {
+        let head = Arc::new(Stage::new(capacity));
+
+        PipeBuilder {
+            head: head.clone(),
+            tail: head,
+            capacity,
+            stats: Vec::new(),
+            workers: Vec::new(),
         }
     }
+}
 
-    fn head(&self) -> &Stage {
-        &self.stages[0]
+impl<I, O> PipeBuilder<I, O>
+    where I: Send + 'static,
+          O: Send + 'static
+{
+    fn stage<U, F>(self, f: F) -> PipeBuilder<I, U>
+        where F: Fn(O) -> U + Send + Sync + 'static,
+              U: Send + 'static
+    {
+        self.replicated_stage(1, false, f)
     }
 
-    fn tail(&self) -> &Stage {
-        &self.stages[self.stages.len() - 1]
+    /// Adds a stage that can fail. Its errors come out of `pipe_result` as
+    /// `PipeError::Stage`, numbered from the first stage on as 0.
+    fn try_stage<U, E, F>(self, f: F) -> PipeBuilder<I, U>
+        where F: Fn(O) -> Result<U, E> + Send + Sync + 'static,
+              E: fmt::Display,
+              U: Send + 'static
+    {
+        self.replicated_try_stage(1, false, f)
+    }
+
+    /// Adds a stage that runs on `replicas` threads, all taking items from the
+    /// same buffer. Unless `ordered`, results leave in the order they're done.
+    fn replicated_stage<U, F>(self, replicas: usize, ordered: bool, f: F) -> PipeBuilder<I, U>
+        where F: Fn(O) -> U + Send + Sync + 'static,
+              U: Send + 'static
+    {
+        self.replicated_try_stage(replicas, ordered, move |data| Ok::<U, Infallible>(f(data)))
+    }
+
+    fn replicated_try_stage<U, E, F>(self, replicas: usize, ordered: bool, f: F) -> PipeBuilder<I, U>
+        where F: Fn(O) -> Result<U, E> + Send + Sync + 'static,
+              E: fmt::Display,
+              U: Send + 'static
+    {
+        assert!(replicas > 0);
+
+        let index = self.stats.len();
+
+        let input = self.tail;
+        let output = Arc::new(Stage::new(self.capacity));
+        let fan_in = Arc::new(FanIn::new(replicas, ordered));
+        let stats = Arc::new(Mutex::new(StageStats::new(replicas)));
+        let f = Arc::new(f);
+        let mut workers = self.workers;
+
+        for _ in 0..replicas {
+            let (input, output, fan_in, stats, f) =
+                (input.clone(), output.clone(), fan_in.clone(), stats.clone(), f.clone());
+
+            workers.push(Box::new(move || {
+                worker(index, &input, &output, &fan_in, &stats, &*f);
+            }));
+        }
+
+        let mut all_stats = self.stats;
+        all_stats.push(stats);
+
+        PipeBuilder {
+            head: self.head,
+            tail: output,
+            capacity: self.capacity,
+            stats: all_stats,
+            workers,
+        }
+    }
+
+    fn build(self) -> Arc<Pipe<I, O>> {
+        assert!(!self.workers.is_empty());
+
+        let handles = self.workers.into_iter().map(thread::spawn).collect();
+
+        Arc::new(Pipe {
+            head: self.head,
+            tail: self.tail,
+            active_count: Mutex::new(0),
+            started: Condvar::new(),
+            handles: Mutex::new(handles),
+            stats: self.stats,
+        })
     }
 }
 
-impl Deref for Pipe {
-    type Target = [Stage];
+/// Poisons the pipe around a stage thread if it panics, so the stages and
+/// callers waiting on it fail instead of waiting forever.
+struct PoisonOnPanic<'a, T: 'a, U: 'a> {
+    stage: &'a Stage<T>,
+    next: &'a Stage<U>,
+    fan_in: &'a FanIn,
+}
 
-    fn deref(&self) -> &Self::Target {
-        &self.stages
+impl<'a, T, U> Drop for PoisonOnPanic<'a, T, U> {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            // wake up a replica blocked sending to next before it's needed
+            // on the fan-in
+            poison(self.next);
+            poison(self.stage);
+            self.fan_in.poison();
+        }
     }
 }
 
-fn worker(pipe: &[Stage], stage_idx: usize) {
-    let stage = &pipe[stage_idx];
+fn worker<T, U, E, F>(index: usize, stage: &Stage<T>, next: &Stage<U>, fan_in: &FanIn,
+                      stats: &Mutex<StageStats>, f: &F)
+    where F: Fn(T) -> Result<U, E>,
+          E: fmt::Display
+{
+    let _poison = PoisonOnPanic { stage, next, fan_in };
 
-    match stage.stage_data.lock() {
-        Err(e) => panic!(format!("Error trying to lock mutex in worker for stage_index {} : {}",
-                         stage_idx,
-                         e)),
-        Ok(mut guard) => {
-            loop {
-                while !guard.unprocessed {
+    loop {
+        let waiting = Instant::now();
+        let (seq, data) = match stage.stage_data.lock() {
+            Err(e) => panic!("Error trying to lock mutex in worker: {}", e),
+            Ok(mut guard) => {
+                while guard.data.is_empty() && !guard.closed {
                     guard = stage.avail.wait(guard).unwrap();
                 }
 
-                send(&pipe[stage_idx + 1], guard.data + 1);
-                guard.unprocessed = false;
-                stage.ready.notify_one();
-            }
-        },
+                match guard.data.pop_front() {
+                    // a stage upstream panicked, pass it on
+                    None if guard.poisoned => {
+                        poison(next);
+                        fan_in.poison();
+                        return;
+                    },
+                    // upstream is closed and our slot is empty, pass it on
+                    None => break,
+                    Some(data) => {
+                        let seq = guard.taken;
+                        guard.taken += 1;
+                        stage.ready.notify_one();
+                        (seq, data)
+                    },
+                }
+            },
+        };
+
+        let processing = Instant::now();
+        let starved = processing - waiting;
+
+        // an item that failed upstream skips this stage
+        let result = data.and_then(|data| {
+            f(data).map_err(|e| PipeError::Stage { stage: index, message: e.to_string() })
+        });
+
+        let sending = Instant::now();
+        let processed = sending - processing;
+
+        // don't hold on to our slot while waiting for the next stage, or
+        // closing the pipe couldn't get past a full tail
+        if fan_in.send(next, seq, result).is_err() {
+            // a stage downstream panicked, pass it on
+            poison(stage);
+            fan_in.poison();
+            return;
+        }
+
+        match stats.lock() {
+            Err(e) => panic!("Error trying to lock stats mutex in worker: {}", e),
+            Ok(mut stats) => stats.record(starved, processed, sending.elapsed()),
+        }
     }
+
+    fan_in.leave(next);
 }
 
-fn send(target_stage: &Stage, new_data: u64) {
+fn send<T>(target_stage: &Stage<T>, new_data: Item<T>) -> Result<(), PipeError> {
     match target_stage.stage_data.lock() {
         Err(e) => panic!(format!("Error tyring to lock mutex in send: {}", e)),
         Ok(mut guard) => {
-            while guard.unprocessed {
+            while guard.data.len() >= guard.capacity && !guard.closed {
                 guard = target_stage.ready.wait(guard).unwrap();
             }
-            guard.data = new_data;
-            guard.unprocessed = true;
+            if guard.poisoned {
+                return Err(PipeError::Poisoned);
+            }
+            if guard.closed {
+                return Err(PipeError::Closed);
+            }
+            guard.data.push_back(new_data);
             target_stage.avail.notify_one();
+            Ok(())
+        },
+    }
+}
+
+fn close<T>(stage: &Stage<T>) {
+    match stage.stage_data.lock() {
+        Err(e) => panic!("Error trying to lock mutex in close: {}", e),
+        Ok(mut guard) => {
+            guard.closed = true;
+            stage.avail.notify_all();
+            stage.ready.notify_all();
         },
     }
 }
 
-fn create_pipe(stages: usize) -> Arc<Pipe> {
+/// Closes the head of the pipe, so no more items can be started, and wakes
+/// up whoever waits for the next one in `wait_started`.
+fn close_head<I, O>(pipe: &Pipe<I, O>) {
+    close(pipe.head());
+
+    match pipe.active_count.lock() {
+        Err(e) => panic!("Error trying to lock active_count mutex in close_head: {}", e),
+        Ok(_active_count) => pipe.started.notify_all(),
+    }
+}
+
+/// Blocks until an item is in the pipe or the pipe's head is closed.
+fn wait_started<I, O>(pipe: &Pipe<I, O>) {
+    match pipe.active_count.lock() {
+        Err(e) => panic!("Error trying to lock active_count mutex in wait_started: {}", e),
+        Ok(mut active_count) => {
+            while *active_count == 0 && !is_closed(pipe.head()) {
+                active_count = pipe.started.wait(active_count).unwrap();
+            }
+        },
+    }
+}
+
+fn is_closed<T>(stage: &Stage<T>) -> bool {
+    match stage.stage_data.lock() {
+        Err(e) => panic!("Error trying to lock mutex in is_closed: {}", e),
+        Ok(guard) => guard.closed,
+    }
+}
+
+/// Closes the stage and throws away its items. This runs while a thread is
+/// panicking, so a poisoned mutex is no reason to give up.
+fn poison<T>(stage: &Stage<T>) {
+    let mut guard = match stage.stage_data.lock() {
+        Err(e) => e.into_inner(),
+        Ok(guard) => guard,
+    };
+
+    guard.data.clear();
+    guard.closed = true;
+    guard.poisoned = true;
+    stage.avail.notify_all();
+    stage.ready.notify_all();
+}
+
+fn create_pipe(stages: usize, capacity: usize, replicas: usize) -> Arc<Pipe<u64, u64>> {
     assert!(stages > 0);
-    let pipe = Arc::new(Pipe::new(stages));
 
-    for i in 0..stages {
-        let pipe = pipe.clone();
+    add_stages(PipeBuilder::with_capacity(capacity), &vec![Op::Add(1); stages], replicas).build()
+}
 
-        thread::spawn(move || {
-            worker(&pipe, i);
-        });
+/// An arithmetic stage, as given on the command line.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Op {
+    Add(u64),
+    Sub(u64),
+    Mul(u64),
+    Div(u64),
+    Rem(u64),
+    Square,
+}
+
+impl Op {
+    /// Parses one of `+N`, `-N`, `*N`, `/N`, `%N` or `sq`.
+    fn parse(spec: &str) -> Option<Op> {
+        let spec = spec.trim();
+        if spec == "sq" {
+            return Some(Op::Square);
+        }
+
+        let mut chars = spec.chars();
+        let op = chars.next()?;
+        let n = chars.as_str().parse::<u64>().ok()?;
+
+        match op {
+            '+' => Some(Op::Add(n)),
+            '-' => Some(Op::Sub(n)),
+            '*' => Some(Op::Mul(n)),
+            '/' if n > 0 => Some(Op::Div(n)),
+            '%' if n > 0 => Some(Op::Rem(n)),
+            _ => None,
+        }
     }
+}
 
-    pipe
+/// Parses a comma separated list of stages like `+3,*2,sq,%7`.
+fn parse_spec(spec: &str) -> Option<Vec<Op>> {
+    spec.split(',').map(Op::parse).collect()
 }
 
-fn pipe_start(pipe: &Pipe, data: u64) {
+/// Adds a stage for each of `ops`, each running on `replicas` threads and
+/// keeping the items in order. Stages that would overflow fail on the item
+/// instead.
+fn add_stages<I: Send + 'static>(builder: PipeBuilder<I, u64>, ops: &[Op], replicas: usize) -> PipeBuilder<I, u64> {
+    ops.iter().fold(builder, |builder, &op| {
+        let checked = |builder: PipeBuilder<I, u64>, f: fn(u64, u64) -> Option<u64>, n, error| {
+            builder.replicated_try_stage(replicas, true, move |data: u64| f(data, n).ok_or(error))
+        };
+
+        match op {
+            Op::Add(n) => checked(builder, u64::checked_add, n, "overflow"),
+            Op::Sub(n) => checked(builder, u64::checked_sub, n, "underflow"),
+            Op::Mul(n) => checked(builder, u64::checked_mul, n, "overflow"),
+            Op::Div(n) => builder.replicated_stage(replicas, true, move |data| data / n),
+            Op::Rem(n) => builder.replicated_stage(replicas, true, move |data| data % n),
+            Op::Square => builder.replicated_try_stage(replicas, true, |data: u64| data.checked_mul(data).ok_or("overflow")),
+        }
+    })
+}
+
+fn pipe_start<I, O>(pipe: &Pipe<I, O>, data: I) -> Result<(), PipeError> {
     match pipe.active_count.lock() {
         Err(e) => panic!(format!("Error trying to lock active_count mutex in pipe_start: {}", e)),
         Ok(mut active_count) => {
             *active_count += 1;
+            pipe.started.notify_all();
         },
     }
-    
-    send(pipe.head(), data);
+
+    send(pipe.head(), Ok(data)).inspect_err(|_| {
+        match pipe.active_count.lock() {
+            Err(e) => panic!("Error trying to lock active_count mutex in pipe_start: {}", e),
+            Ok(mut active_count) => *active_count -= 1,
+        }
+    })
+}
+
+/// How long to wait for a result at the end of the pipe.
+enum Wait {
+    Block,
+    Poll,
+    Until(Instant),
+}
+
+/// Blocks until the next result comes out of the pipe. Fails with `Empty`
+/// if no items are in the pipe, and with `Stage` if a stage failed on the
+/// item.
+fn pipe_result<I, O>(pipe: &Pipe<I, O>) -> Result<O, PipeError> {
+    receive(pipe, Wait::Block)
+}
+
+/// Returns the next result if one is waiting at the end of the pipe.
+fn pipe_try_result<I, O>(pipe: &Pipe<I, O>) -> Result<O, PipeError> {
+    receive(pipe, Wait::Poll)
+}
+
+fn pipe_result_timeout<I, O>(pipe: &Pipe<I, O>, timeout: Duration) -> Result<O, PipeError> {
+    receive(pipe, Wait::Until(Instant::now() + timeout))
+}
+
+/// Waits for all items in the pipe and returns their results.
+fn pipe_drain<I, O>(pipe: &Pipe<I, O>) -> Vec<Item<O>> {
+    let mut results = Vec::new();
+
+    loop {
+        match pipe_result(pipe) {
+            Err(PipeError::Empty) | Err(PipeError::Closed) => break,
+            Err(PipeError::Poisoned) => {
+                results.push(Err(PipeError::Poisoned));
+                break;
+            },
+            result => results.push(result),
+        }
+    }
+
+    results
 }
 
-fn pipe_result(pipe: &Pipe) -> u64 {
-    let empty = match pipe.active_count.lock() {
-        Err(e) => panic!(format!("Error trying to lock active_count mutex in pipe_result: {}", e)),
+fn receive<I, O>(pipe: &Pipe<I, O>, wait: Wait) -> Result<O, PipeError> {
+    // claim one of the active items before waiting for its result
+    match pipe.active_count.lock() {
+        Err(e) => panic!("Error trying to lock active_count mutex in pipe_result: {}", e),
         Ok(mut active_count) => {
-            if *active_count <= 0 {
-                true
-            } else {
-                *active_count -= 1;
-                false
+            if *active_count == 0 {
+                return Err(PipeError::Empty);
+            }
+            *active_count -= 1;
+        },
+    }
+
+    let tail = pipe.tail();
+    let error = match tail.stage_data.lock() {
+        Err(e) => panic!("Error trying to lock stage_data mutex in pipe_result: {}", e),
+        Ok(mut stage_data) => {
+            let mut error = PipeError::NotReady;
+
+            while stage_data.data.is_empty() && !stage_data.closed {
+                stage_data = match wait {
+                    Wait::Block => tail.avail.wait(stage_data).unwrap(),
+                    Wait::Poll => {
+                        error = PipeError::NotReady;
+                        break;
+                    },
+                    Wait::Until(deadline) => {
+                        let now = Instant::now();
+                        if now >= deadline {
+                            error = PipeError::Timeout;
+                            break;
+                        }
+                        tail.avail.wait_timeout(stage_data, deadline - now).unwrap().0
+                    },
+                };
+            }
+
+            match stage_data.data.pop_front() {
+                Some(result) => {
+                    tail.ready.notify_one();
+                    return result;
+                },
+                // the items still in the pipe are lost
+                None if stage_data.poisoned => return Err(PipeError::Poisoned),
+                // pipe_close() flushed the pipe and reset the count already
+                None if stage_data.closed => return Err(PipeError::Closed),
+                None => error,
             }
         },
     };
 
-    if empty {
-        return 0;
+    // we didn't get a result, so give the claimed item back
+    match pipe.active_count.lock() {
+        Err(e) => panic!("Error trying to lock active_count mutex in pipe_result: {}", e),
+        Ok(mut active_count) => *active_count += 1,
     }
 
+    Err(error)
+}
+
+/// Closes the pipe: items already started run through all stages and are
+/// returned, every stage thread exits once its upstream is closed and is
+/// joined. Starting items on a closed pipe fails. If a stage panicked, the
+/// results end in `PipeError::Poisoned`.
+fn pipe_close<I, O>(pipe: &Pipe<I, O>) -> Vec<Item<O>> {
+    close_head(pipe);
+
+    let mut flushed = Vec::new();
     let tail = pipe.tail();
-    match tail.stage_data.lock() {
-        Err(e) => panic!(format!("Error trying to lock stage_data mutex in pipe_result: {}", e)),
+
+    let poisoned = match tail.stage_data.lock() {
+        Err(e) => panic!("Error trying to lock stage_data mutex in pipe_close: {}", e),
         Ok(mut stage_data) => {
-            while !stage_data.unprocessed {
-                stage_data = tail.avail.wait(stage_data).unwrap();
+            loop {
+                while stage_data.data.is_empty() && !stage_data.closed {
+                    stage_data = tail.avail.wait(stage_data).unwrap();
+                }
+
+                match stage_data.data.pop_front() {
+                    None => break stage_data.poisoned,
+                    Some(result) => {
+                        flushed.push(result);
+                        tail.ready.notify_one();
+                    },
+                }
             }
-            let result = stage_data.data;
-            stage_data.unprocessed = false;
-            
-            tail.ready.notify_one();
-            
-            result
         },
+    };
+
+    let handles = match pipe.handles.lock() {
+        Err(e) => panic!("Error trying to lock handles mutex in pipe_close: {}", e),
+        Ok(mut handles) => std::mem::take(&mut *handles),
+    };
+
+    // a stage that panicked has poisoned the pipe already
+    for handle in handles {
+        let _ = handle.join();
+    }
+
+    if poisoned {
+        flushed.push(Err(PipeError::Poisoned));
+    }
+
+    match pipe.active_count.lock() {
+        Err(e) => panic!("Error trying to lock active_count mutex in pipe_close: {}", e),
+        Ok(mut active_count) => *active_count = 0,
+    }
+
+    flushed
+}
+
+/// Where the stages spent their time so far. Once `pipe_close` returned, the
+/// stats cover every item.
+fn pipe_stats<I, O>(pipe: &Pipe<I, O>) -> PipeStats {
+    let stages = pipe.stats.iter().map(|stats| {
+        match stats.lock() {
+            Err(e) => panic!("Error trying to lock stats mutex in pipe_stats: {}", e),
+            Ok(stats) => stats.clone(),
+        }
+    }).collect();
+
+    PipeStats { stages }
+}
+
+impl<I, O> Drop for Pipe<I, O> {
+    fn drop(&mut self) {
+        if !thread::panicking() {
+            pipe_close(self);
+        }
+    }
+}
+
+/// Lets an iterator feed a pipe, as in `input.into_iter().through(pipe)`.
+trait Through: Iterator + Sized {
+    /// Starts every item on `pipe` from a producer thread, closing the pipe
+    /// after the last one, and iterates over the results as they come out.
+    fn through<O: Send + 'static>(self, pipe: Arc<Pipe<Self::Item, O>>) -> PipeIter<Self::Item, O>;
+}
+
+impl<It> Through for It
+    where It: Iterator + Send + 'static,
+          It::Item: Send + 'static
+{
+    fn through<O: Send + 'static>(self, pipe: Arc<Pipe<It::Item, O>>) -> PipeIter<It::Item, O> {
+        let producer_pipe = pipe.clone();
+        let producer = thread::spawn(move || {
+            // close the pipe even if the iterator panics, or the consumer
+            // would wait for more results forever
+            let _close = CloseHead(&producer_pipe);
+
+            for data in self {
+                // the consumer went away and closed the pipe
+                if pipe_start(&producer_pipe, data).is_err() {
+                    break;
+                }
+            }
+        });
+
+        PipeIter {
+            pipe,
+            producer: Some(producer),
+        }
+    }
+}
+
+struct CloseHead<'a, I: 'a, O: 'a>(&'a Pipe<I, O>);
+
+impl<'a, I, O> Drop for CloseHead<'a, I, O> {
+    fn drop(&mut self) {
+        close_head(self.0);
+    }
+}
+
+/// The results of a pipe fed by [`Through::through`], in the order they come
+/// out of the last stage. If a stage panicked, they end in
+/// `PipeError::Poisoned`.
+struct PipeIter<I, O> {
+    pipe: Arc<Pipe<I, O>>,
+    producer: Option<JoinHandle<()>>,
+}
+
+impl<I, O> PipeIter<I, O> {
+    fn join_producer(&mut self) {
+        if let Some(producer) = self.producer.take() {
+            if producer.join().is_err() && !thread::panicking() {
+                panic!("The pipe's producer panicked.");
+            }
+        }
+    }
+}
+
+impl<I, O> Iterator for PipeIter<I, O> {
+    type Item = Item<O>;
+
+    fn next(&mut self) -> Option<Item<O>> {
+        // the producer is joined once we're done
+        self.producer.as_ref()?;
+
+        loop {
+            // once the head is closed every item has been started, so an
+            // empty pipe stays empty
+            let started_all = is_closed(self.pipe.head());
+
+            match pipe_result(&self.pipe) {
+                Err(PipeError::Empty) if !started_all => wait_started(&self.pipe),
+                Err(PipeError::Empty) | Err(PipeError::Closed) => {
+                    self.join_producer();
+                    return None;
+                },
+                Err(PipeError::Poisoned) => {
+                    self.join_producer();
+                    return Some(Err(PipeError::Poisoned));
+                },
+                result => return Some(result),
+            }
+        }
+    }
+}
+
+impl<I, O> Drop for PipeIter<I, O> {
+    fn drop(&mut self) {
+        // stop a producer that is still waiting to start items
+        close_head(&self.pipe);
+        self.join_producer();
+    }
+}
+
+const BENCH_STAGES: usize = 3;
+const BENCH_MAX_DELAY_MICROS: u64 = 200;
+
+fn bench_work(data: u64) -> u64 {
+    let delay = rand::random::<u64>() % BENCH_MAX_DELAY_MICROS;
+    thread::sleep(Duration::from_micros(delay));
+    data + 1
+}
+
+/// Pushes `items` through stages that each take a random amount of time, and
+/// reports the throughput. The more items a stage can buffer, the less a slow
+/// item holds up the stages in front of it. Every stage runs on `replicas`
+/// threads and keeps the items in order.
+fn bench(capacity: usize, replicas: usize, items: u64) {
+    let pipe = (0..BENCH_STAGES).fold(PipeBuilder::with_capacity(capacity), |builder, _| {
+        // a single replica keeps the items in order by itself
+        if replicas == 1 {
+            builder.stage(bench_work)
+        } else {
+            builder.replicated_stage(replicas, true, bench_work)
+        }
+    }).build();
+
+    let start = Instant::now();
+
+    let mut received = 0;
+    for result in (0..items).through(pipe.clone()) {
+        assert_eq!(result, Ok(received + BENCH_STAGES as u64));
+        received += 1;
+    }
+    assert_eq!(received, items);
+
+    let elapsed = start.elapsed();
+    println!("{} items through {} stages with capacity {} and {} replicas in {:?} ({:.0} items/s)",
+             items, BENCH_STAGES, capacity, replicas, elapsed,
+             items as f64 / elapsed.as_secs_f64());
+
+    pipe_close(&pipe);
+    println!();
+    print!("{}", pipe_stats(&pipe));
+}
+
+/// Runs the numbers read from stdin through the stages until EOF, and prints
+/// one result per line. Returns whether no item failed.
+fn batch(capacity: usize, replicas: usize, ops: &[Op], stats: bool) -> bool {
+    let pipe = add_stages(PipeBuilder::with_capacity(capacity)
+        .try_stage(|line: String| line.trim().parse::<u64>()), ops, replicas).build();
+
+    let lines = BufReader::new(std::io::stdin()).lines()
+        .map(|line| line.expect("Error trying to read line of input."))
+        .filter(|line| !line.trim().is_empty());
+
+    let mut ok = true;
+    for result in lines.through(pipe.clone()) {
+        match result {
+            Ok(result) => println!("{}", result),
+            Err(e) => {
+                eprintln!("{}", batch_error(e));
+                ok = false;
+            },
+        }
+    }
+
+    if stats {
+        pipe_close(&pipe);
+
+        // leave out the parse stage, so the table matches the spec
+        let mut stats = pipe_stats(&pipe);
+        stats.stages.remove(0);
+        eprint!("{}", stats);
+    }
+
+    ok
+}
+
+/// Stage 0 of the batch pipe parses the lines, so the stages of the spec
+/// start at 1 in the pipe. Reports them as numbered in the spec instead.
+fn batch_error(e: PipeError) -> String {
+    match e {
+        PipeError::Stage { stage: 0, message } => format!("Input is not a number: {}", message),
+        PipeError::Stage { stage, message } => PipeError::Stage { stage: stage - 1, message }.to_string(),
+        e => e.to_string(),
+    }
+}
+
+fn print_result(result: Item<u64>) {
+    match result {
+        Err(e) => println!("{}", e),
+        Ok(result) => println!("result: {}", result),
+    }
+}
+
+fn abort_with_usage_message() -> ! {
+    eprintln!("usage: pipe [--capacity N] [--replicas N] [--batch] [--stats] [STAGES]");
+    eprintln!("       pipe [--capacity N] [--replicas N] --bench ITEMS");
+    eprintln!("STAGES is a comma separated list of +N, -N, *N, /N, %N and sq, e.g. +3,*2,sq,%7");
+    std::process::exit(1)
+}
+
+fn flag_value<A: Iterator<Item = String>>(args: &mut A) -> u64 {
+    match args.next().map(|v| v.parse::<u64>()) {
+        Some(Ok(v)) if v > 0 => v,
+        _ => abort_with_usage_message(),
     }
 }
 
 fn main() {
-    let pipe = create_pipe(2);
+    let mut capacity = 1;
+    let mut replicas = 1;
+    let mut bench_items = None;
+    let mut batch_mode = false;
+    let mut stats = false;
+    let mut ops = None;
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--capacity" => capacity = flag_value(&mut args) as usize,
+            "--replicas" => replicas = flag_value(&mut args) as usize,
+            "--bench" => bench_items = Some(flag_value(&mut args)),
+            "--batch" => batch_mode = true,
+            "--stats" => stats = true,
+            _ if ops.is_none() && !arg.starts_with("--") => {
+                ops = Some(parse_spec(&arg).unwrap_or_else(|| abort_with_usage_message()));
+            },
+            _ => abort_with_usage_message(),
+        }
+    }
+
+    if let Some(items) = bench_items {
+        bench(capacity, replicas, items);
+        return;
+    }
+
+    if batch_mode {
+        let ops = ops.unwrap_or_else(|| vec![Op::Add(1); 2]);
+        if !batch(capacity, replicas, &ops, stats) {
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let pipe = match ops {
+        None => create_pipe(2, capacity, replicas),
+        Some(ops) => add_stages(PipeBuilder::with_capacity(capacity), &ops, replicas).build(),
+    };
 
-    println!("Enter integer values, or \"=\" for next result");
+    println!("Enter integer values, \"=\" for next result, \"=N\" to wait at most N seconds for it,");
+    println!("\"?\" for a result that is ready or \"=*\" for all results");
 
     loop {
         let mut buffer = String::with_capacity(128);
-        
+
         print!("Data> ");
         std::io::stdout().flush().expect("Error flushing stdout.");
 
         match std::io::stdin().read_line(&mut buffer) {
             Err(e) => panic!(format!("Error trying to read line of input: {}", e)),
+            Ok(0) => {
+                println!();
+                for result in pipe_close(&pipe) {
+                    print_result(result);
+                }
+                if stats {
+                    print!("{}", pipe_stats(&pipe));
+                }
+                break;
+            },
             Ok(n) => {
                 if n > 0 {
-                    if buffer.chars().next() == Some('=') {
-                        let result = pipe_result(&pipe);
-                        if result == 0 {
-                            println!("Pipe is empty.")
-                        } else {
-                            println!("result: {}", result);
+                    if buffer.trim() == "=*" {
+                        for result in pipe_drain(&pipe) {
+                            print_result(result);
                         }
+                    } else if buffer.starts_with('=') {
+                        let result = match buffer.trim()[1..].trim() {
+                            "" => pipe_result(&pipe),
+                            seconds => {
+                                let seconds = seconds.parse::<u64>().expect("Error trying to read seconds to wait.");
+                                pipe_result_timeout(&pipe, Duration::from_secs(seconds))
+                            },
+                        };
+                        print_result(result);
+                    } else if buffer.trim() == "?" {
+                        print_result(pipe_try_result(&pipe));
                     } else {
                         let new_data = buffer.trim().parse::<u64>().expect("Error trying to read input as number.");
-                        pipe_start(&pipe, new_data);
+                        if let Err(e) = pipe_start(&pipe, new_data) {
+                            panic!("Error trying to start pipe: {}", e);
+                        }
                     }
                 }
             }
@@ -181,3 +1046,231 @@ fn main() {
     }
 }
 
+#[cfg(test)]
+mod test {
+    use super::{PipeBuilder, PipeError, create_pipe, pipe_start, pipe_result, pipe_close};
+    use super::{pipe_try_result, pipe_result_timeout, pipe_drain};
+    use super::{Op, parse_spec, add_stages, pipe_stats, batch_error};
+    use super::Through;
+    use std::time::Duration;
+
+    #[test]
+    fn increment_stages() {
+        let pipe = create_pipe(3, 1, 1);
+
+        pipe_start(&pipe, 1).unwrap();
+        pipe_start(&pipe, 10).unwrap();
+
+        assert_eq!(pipe_result(&pipe), Ok(4));
+        assert_eq!(pipe_result(&pipe), Ok(13));
+        assert_eq!(pipe_result(&pipe), Err(PipeError::Empty));
+    }
+
+    #[test]
+    fn stages_change_item_type() {
+        let pipe = PipeBuilder::with_capacity(1)
+            .stage(|line: String| line.trim().parse::<i64>().unwrap())
+            .stage(|n| n * n)
+            .stage(|n: i64| format!("<{}>", n))
+            .build();
+
+        pipe_start(&pipe, " -7 ".to_string()).unwrap();
+        assert_eq!(pipe_result(&pipe), Ok("<49>".to_string()));
+    }
+
+    #[test]
+    fn close_flushes_and_rejects_new_items() {
+        let pipe = create_pipe(2, 1, 1);
+
+        pipe_start(&pipe, 1).unwrap();
+        pipe_start(&pipe, 2).unwrap();
+        pipe_start(&pipe, 3).unwrap();
+
+        assert_eq!(pipe_close(&pipe), vec![Ok(3), Ok(4), Ok(5)]);
+        assert_eq!(pipe_result(&pipe), Err(PipeError::Empty));
+        assert_eq!(pipe_start(&pipe, 4), Err(PipeError::Closed));
+        assert_eq!(pipe_close(&pipe), vec![]);
+    }
+
+    #[test]
+    fn buffered_stages_take_several_items() {
+        let pipe = create_pipe(2, 4, 1);
+
+        // 3 buffers of 4 items each, plus one item in the hands of each stage
+        for i in 0..14 {
+            pipe_start(&pipe, i).unwrap();
+        }
+
+        assert_eq!(pipe_close(&pipe), (2..16).map(Ok).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn zero_is_a_result() {
+        let pipe = PipeBuilder::with_capacity(1).stage(|data: i64| data - 1).build();
+
+        assert_eq!(pipe_try_result(&pipe), Err(PipeError::Empty));
+        pipe_start(&pipe, 1).unwrap();
+        assert_eq!(pipe_result_timeout(&pipe, Duration::from_secs(5)), Ok(0));
+    }
+
+    #[test]
+    fn result_timeout_keeps_item_active() {
+        let pipe = PipeBuilder::with_capacity(1).stage(|data: u64| {
+            std::thread::sleep(Duration::from_millis(50));
+            data
+        }).build();
+
+        pipe_start(&pipe, 7).unwrap();
+        assert_eq!(pipe_try_result(&pipe), Err(PipeError::NotReady));
+        assert_eq!(pipe_result_timeout(&pipe, Duration::from_millis(1)), Err(PipeError::Timeout));
+        assert_eq!(pipe_result(&pipe), Ok(7));
+    }
+
+    #[test]
+    fn drain_returns_outstanding_results() {
+        let pipe = create_pipe(2, 2, 1);
+
+        for i in 0..5 {
+            pipe_start(&pipe, i).unwrap();
+        }
+
+        assert_eq!(pipe_drain(&pipe), vec![Ok(2), Ok(3), Ok(4), Ok(5), Ok(6)]);
+        assert!(pipe_drain(&pipe).is_empty());
+    }
+
+    fn jitter(data: u64) -> u64 {
+        std::thread::sleep(Duration::from_micros(data * 7919 % 500));
+        data
+    }
+
+    #[test]
+    fn replicated_stage_unordered() {
+        let pipe = PipeBuilder::with_capacity(4)
+            .replicated_stage(4, false, jitter)
+            .build();
+
+        let mut results = (0..100).through(pipe).collect::<Result<Vec<_>, _>>().unwrap();
+        results.sort();
+        assert_eq!(results, (0..100).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn replicated_stage_ordered() {
+        let pipe = PipeBuilder::with_capacity(4)
+            .replicated_stage(4, true, jitter)
+            .stage(|data| data + 1)
+            .build();
+
+        assert_eq!((0..100).through(pipe).collect::<Result<Vec<_>, _>>(), Ok((1..101).collect()));
+    }
+
+    #[test]
+    fn through_single_slot_stages() {
+        let results = vec![1, 2, 3, 4, 5].into_iter()
+            .through(create_pipe(3, 1, 1))
+            .collect::<Vec<_>>();
+
+        assert_eq!(results, vec![Ok(4), Ok(5), Ok(6), Ok(7), Ok(8)]);
+    }
+
+    #[test]
+    fn dropping_through_stops_the_producer() {
+        let mut results = (0..).through(create_pipe(2, 1, 1));
+
+        assert_eq!(results.next(), Some(Ok(2)));
+        assert_eq!(results.next(), Some(Ok(3)));
+    }
+
+    #[test]
+    fn stage_errors_are_tagged_and_passed_on() {
+        let pipe = PipeBuilder::with_capacity(1)
+            .stage(|data: u64| data * 2)
+            .try_stage(|data| if data % 3 == 0 { Err("multiple of 3") } else { Ok(data) })
+            .stage(|data| data + 1)
+            .build();
+
+        let results = (1..5).through(pipe).collect::<Vec<_>>();
+
+        assert_eq!(results, vec![
+            Ok(3),
+            Ok(5),
+            Err(PipeError::Stage { stage: 1, message: "multiple of 3".to_string() }),
+            Ok(9),
+        ]);
+    }
+
+    #[test]
+    fn panicking_stage_poisons_the_pipe() {
+        let pipe = PipeBuilder::with_capacity(2)
+            .stage(|data: u64| data)
+            .replicated_stage(2, true, |data: u64| if data == 3 { panic!("stage test panic") } else { data })
+            .stage(|data| data)
+            .build();
+
+        let results = (0..).through(pipe).collect::<Vec<_>>();
+
+        assert_eq!(results.last(), Some(&Err(PipeError::Poisoned)));
+        assert!(results.len() <= 4);
+    }
+
+    #[test]
+    fn overflow_is_a_stage_error() {
+        let pipe = create_pipe(1, 1, 1);
+        pipe_start(&pipe, u64::MAX).unwrap();
+        assert_eq!(pipe_result(&pipe), Err(PipeError::Stage { stage: 0, message: "overflow".to_string() }));
+    }
+
+    #[test]
+    fn spec_stages() {
+        assert_eq!(parse_spec("+3,*2,sq,%7"), Some(vec![Op::Add(3), Op::Mul(2), Op::Square, Op::Rem(7)]));
+        assert_eq!(parse_spec("-1, /4"), Some(vec![Op::Sub(1), Op::Div(4)]));
+
+        for spec in &["", "+", "sq2", "x3", "/0", "%0", "+1,,+1", "+-1"] {
+            assert_eq!(parse_spec(spec), None, "{:?}", spec);
+        }
+
+        let pipe = add_stages(PipeBuilder::with_capacity(1), &parse_spec("+3,*2,sq,%7,-1").unwrap(), 1).build();
+        let results = vec![2, 4].into_iter().through(pipe).collect::<Vec<_>>();
+
+        // (2 + 3) * 2 = 10, 100 % 7 = 2; (4 + 3) * 2 = 14, 196 % 7 = 0
+        assert_eq!(results, vec![
+            Ok(1),
+            Err(PipeError::Stage { stage: 4, message: "underflow".to_string() }),
+        ]);
+    }
+
+    #[test]
+    fn batch_errors_count_stages_as_in_spec() {
+        let parse_error = PipeError::Stage { stage: 0, message: "invalid digit found in string".to_string() };
+        let overflow = PipeError::Stage { stage: 1, message: "overflow".to_string() };
+
+        assert_eq!(batch_error(parse_error), "Input is not a number: invalid digit found in string");
+        assert_eq!(batch_error(overflow), "Stage 0 failed: overflow");
+        assert_eq!(batch_error(PipeError::Poisoned), PipeError::Poisoned.to_string());
+    }
+
+    #[test]
+    fn stats_find_the_slow_stage() {
+        let pipe = PipeBuilder::with_capacity(1)
+            .stage(|data: u64| data)
+            .replicated_stage(2, true, |data: u64| {
+                std::thread::sleep(Duration::from_millis(2));
+                data
+            })
+            .stage(|data| data)
+            .build();
+
+        assert_eq!((0..20).through(pipe.clone()).count(), 20);
+        pipe_close(&pipe);
+
+        let stats = pipe_stats(&pipe);
+        assert_eq!(stats.stages.iter().map(|s| s.items).collect::<Vec<_>>(), vec![20, 20, 20]);
+        assert_eq!(stats.stages[1].replicas, 2);
+        assert!(stats.stages[1].processing >= Duration::from_millis(40));
+        assert_eq!(stats.bottleneck(), Some(1));
+
+        // the first stage waits for the slow one, the last one waits for it
+        assert!(stats.stages[0].blocked > stats.stages[0].processing);
+        assert!(stats.stages[2].starved > stats.stages[2].processing);
+    }
+}
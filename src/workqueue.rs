@@ -4,6 +4,7 @@ use std::sync::{Mutex, Condvar, Arc};
 use std::collections::VecDeque;
 use std::fmt;
 use std::thread;
+use std::time::{Duration, Instant};
 
 #[derive(Debug)]
 enum WorkqueueError {
@@ -58,6 +59,17 @@ impl<T, F: ?Sized> Workqueue<T, F> {
             parallelism: parallelism,
         }
     }
+
+    fn metrics(&self) -> WorkqueueMetrics {
+        match self.inner.state.lock() {
+            Err(e) => panic!("Unable to lock workqueue state to read metrics. {}", e),
+            Ok(state) => {
+                let mut metrics = state.metrics.clone();
+                metrics.queue_length = state.tasks.len();
+                metrics
+            },
+        }
+    }
 }
 
 impl<T, F: ?Sized> Workqueue<T, F>
@@ -65,7 +77,50 @@ impl<T, F: ?Sized> Workqueue<T, F>
           T: Clone + Send + Sync + 'static
 {
     fn add_task(&self, task: T) -> Result<(), WorkqueueError> {
+        if self.push_task(task)? {
+            self.new_worker();
+        }
+
+        Ok(())
+    }
+
+    fn new_worker(&self) {
+        let workqueue = self.register_worker();
+        
+        thread::spawn(move || {
+            Workqueue::worker_routine(workqueue)
+        });
+    }
+}
 
+impl<T, F: ?Sized> Workqueue<T, F>
+    where F: Fn(T) -> T + Send + Sync,
+          T: Clone + Send
+{
+    /// Runs `f` with a workqueue whose routine and tasks may borrow from the
+    /// caller's stack. All tasks are finished and all workers have exited by
+    /// the time this returns the per-worker results.
+    fn scoped<'env, S>(routine: Box<F>,
+                       parallelism: usize,
+                       f: S) -> Result<Vec<Vec<T>>, WorkqueueError>
+        where S: for<'scope> FnOnce(&ScopedWorkqueue<'scope, 'env, T, F>),
+              T: 'env,
+              F: 'env
+    {
+        thread::scope(|scope| {
+            let workqueue = ScopedWorkqueue {
+                scope,
+                workqueue: Workqueue::new(routine, parallelism),
+            };
+
+            f(&workqueue);
+
+            workqueue.workqueue.quit()
+        })
+    }
+
+    /// Queues `task` and returns whether a new worker should be started for it.
+    fn push_task(&self, task: T) -> Result<bool, WorkqueueError> {
         let mut start_new_worker = false;
         
         match self.inner.state.lock() {
@@ -75,7 +130,12 @@ impl<T, F: ?Sized> Workqueue<T, F>
                     return Err(WorkqueueError::Quit);
                 }
 
-                state.tasks.push_back(task);
+                state.tasks.push_back(QueuedTask::new(task));
+
+                let queue_length = state.tasks.len();
+                if queue_length > state.metrics.peak_queue_length {
+                    state.metrics.peak_queue_length = queue_length;
+                }
 
                 if state.idle_counter > 0 {
                     self.inner.work_present.notify_one();
@@ -85,26 +145,20 @@ impl<T, F: ?Sized> Workqueue<T, F>
             }
         }
 
-        if start_new_worker {
-            self.new_worker();
-        }
-
-        Ok(())
+        Ok(start_new_worker)
     }
 
-    fn new_worker(&self) {
+    /// Counts a new worker thread and hands out the state it should run on.
+    fn register_worker(&self) -> Arc<RawWorkqueue<T, F>> {
         match self.inner.state.lock() {
             Err(e) => panic!("Unable to lock workqueue to start new worker: {}", e),
             Ok(mut state) => {
                 state.thread_counter += 1;
+                state.metrics.threads_spawned += 1;
             },
         }
         
-        let workqueue = self.inner.clone();
-        
-        thread::spawn(move || {
-            Workqueue::worker_routine(workqueue)
-        });
+        self.inner.clone()
     }
 
     fn quit(&self) -> Result<Vec<Vec<T>>, WorkqueueError> {
@@ -123,6 +177,7 @@ impl<T, F: ?Sized> Workqueue<T, F>
     }
 
     fn worker_routine(workqueue: Arc<RawWorkqueue<T, F>>) {
+        let _registered = WorkerGuard { workqueue: &workqueue };
         let mut tasks_completed = vec![];
         
         loop {
@@ -153,7 +208,7 @@ impl<T, F: ?Sized> Workqueue<T, F>
                     }
 
                     should_quit = state.quit;
-                    state.tasks.pop_front()
+                    state.tasks.pop_front().map(|t| (t, Instant::now()))
                 },
             };
 
@@ -162,27 +217,76 @@ impl<T, F: ?Sized> Workqueue<T, F>
                     match workqueue.state.lock() {
                         Err(e) => panic!("Failed to get lock to decrease thread count: {}", e),
                         Ok(mut state) => {
-                            state.thread_counter -= 1;
-                            if state.thread_counter == 0 {
-                                workqueue.work_present.notify_all();
+                            // hand in the results before quit() can see this worker gone
+                            state.completed.push(std::mem::take(&mut tasks_completed));
+                            if timedout && !should_quit {
+                                state.metrics.threads_retired += 1;
                             }
                             break;
                         },
                     }
                 },
-                Some(t) => {
-                    tasks_completed.push((workqueue.routine)(t));
+                Some((t, started)) => {
+                    tasks_completed.push((workqueue.routine)(t.task));
+
+                    let timing = TaskTiming {
+                        enqueued: t.enqueued,
+                        started,
+                        finished: Instant::now(),
+                    };
+
+                    match workqueue.state.lock() {
+                        Err(e) => panic!("Failed to lock workqueue to record task timing: {}", e),
+                        Ok(mut state) => state.metrics.record(&timing),
+                    }
                 },
                 _ => unreachable!(),
             }
         }
+    }
+}
 
-        match workqueue.state.lock() {
-            Err(e) => panic!("Failed to lock workqueue after finishing worker_routine: {}", e),
-            Ok(mut state) => {
-                state.completed.push(tasks_completed);
-            },
+/// Takes a worker out of the thread count when it exits, even if the routine
+/// panicked, so `quit()` doesn't wait for it forever.
+struct WorkerGuard<'a, T: 'a, F: ?Sized + 'a> {
+    workqueue: &'a RawWorkqueue<T, F>,
+}
+
+impl<'a, T, F: ?Sized> Drop for WorkerGuard<'a, T, F> {
+    fn drop(&mut self) {
+        let mut state = match self.workqueue.state.lock() {
+            Err(e) => e.into_inner(),
+            Ok(state) => state,
+        };
+
+        state.thread_counter -= 1;
+        if state.thread_counter == 0 {
+            self.workqueue.work_present.notify_all();
+        }
+    }
+}
+
+/// A workqueue whose workers run on the threads of a `std::thread::Scope`,
+/// so its routine and tasks only need to outlive `'env`.
+struct ScopedWorkqueue<'scope, 'env: 'scope, T: 'env, F: ?Sized + 'env> {
+    scope: &'scope thread::Scope<'scope, 'env>,
+    workqueue: Workqueue<T, F>,
+}
+
+impl<'scope, 'env, T, F: ?Sized> ScopedWorkqueue<'scope, 'env, T, F>
+    where F: Fn(T) -> T + Send + Sync,
+          T: Clone + Send
+{
+    fn add_task(&self, task: T) -> Result<(), WorkqueueError> {
+        if self.workqueue.push_task(task)? {
+            let workqueue = self.workqueue.register_worker();
+
+            self.scope.spawn(move || {
+                Workqueue::worker_routine(workqueue)
+            });
         }
+
+        Ok(())
     }
 }
 
@@ -190,8 +294,9 @@ struct WorkqueueState<T> {
     quit: bool,
     thread_counter: usize,
     idle_counter: usize,
-    tasks: VecDeque<T>,
+    tasks: VecDeque<QueuedTask<T>>,
     completed: Vec<Vec<T>>,
+    metrics: WorkqueueMetrics,
 }
 
 impl<T> WorkqueueState<T> {
@@ -202,10 +307,169 @@ impl<T> WorkqueueState<T> {
             idle_counter: 0,
             tasks: VecDeque::new(),
             completed: Vec::new(),
+            metrics: WorkqueueMetrics::new(),
+        }
+    }
+}
+
+struct QueuedTask<T> {
+    task: T,
+    enqueued: Instant,
+}
+
+impl<T> QueuedTask<T> {
+    fn new(task: T) -> Self {
+        QueuedTask {
+            task,
+            enqueued: Instant::now(),
+        }
+    }
+}
+
+/// The points in time a task passed through the workqueue.
+struct TaskTiming {
+    enqueued: Instant,
+    started: Instant,
+    finished: Instant,
+}
+
+impl TaskTiming {
+    fn queue_wait(&self) -> Duration {
+        self.started.duration_since(self.enqueued)
+    }
+
+    fn run_time(&self) -> Duration {
+        self.finished.duration_since(self.started)
+    }
+}
+
+const HISTOGRAM_BUCKETS: usize = 32;
+
+/// Latency histogram with power-of-two microsecond buckets. Bucket 0 holds
+/// samples below 1us, bucket `i` holds samples below `2^i` us; the last bucket
+/// collects everything that doesn't fit anywhere else.
+#[derive(Clone, Debug)]
+struct Histogram {
+    buckets: [u64; HISTOGRAM_BUCKETS],
+    count: u64,
+    total: Duration,
+    min: Duration,
+    max: Duration,
+}
+
+impl Histogram {
+    fn new() -> Self {
+        Histogram {
+            buckets: [0; HISTOGRAM_BUCKETS],
+            count: 0,
+            total: Duration::from_secs(0),
+            min: Duration::from_secs(0),
+            max: Duration::from_secs(0),
+        }
+    }
+
+    fn bucket_index(sample: Duration) -> usize {
+        let micros = sample.as_micros();
+        let bits = (128 - micros.leading_zeros()) as usize;
+        std::cmp::min(bits, HISTOGRAM_BUCKETS - 1)
+    }
+
+    fn record(&mut self, sample: Duration) {
+        self.buckets[Histogram::bucket_index(sample)] += 1;
+
+        if self.count == 0 || sample < self.min {
+            self.min = sample;
+        }
+        if sample > self.max {
+            self.max = sample;
+        }
+
+        self.count += 1;
+        self.total += sample;
+    }
+
+    fn mean(&self) -> Duration {
+        if self.count == 0 {
+            Duration::from_secs(0)
+        } else {
+            self.total / self.count as u32
         }
     }
 }
 
+/// Aggregate statistics about a workqueue's tasks and worker threads.
+#[derive(Clone, Debug)]
+struct WorkqueueMetrics {
+    queue_length: usize,
+    peak_queue_length: usize,
+    threads_spawned: usize,
+    threads_retired: usize,
+    tasks_completed: u64,
+    queue_wait: Histogram,
+    run_time: Histogram,
+}
+
+impl WorkqueueMetrics {
+    fn new() -> Self {
+        WorkqueueMetrics {
+            queue_length: 0,
+            peak_queue_length: 0,
+            threads_spawned: 0,
+            threads_retired: 0,
+            tasks_completed: 0,
+            queue_wait: Histogram::new(),
+            run_time: Histogram::new(),
+        }
+    }
+
+    fn record(&mut self, timing: &TaskTiming) {
+        self.tasks_completed += 1;
+        self.queue_wait.record(timing.queue_wait());
+        self.run_time.record(timing.run_time());
+    }
+}
+
+impl fmt::Display for WorkqueueMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        writeln!(f, "queue length:    {} current, {} peak",
+                 self.queue_length, self.peak_queue_length)?;
+        writeln!(f, "worker threads:  {} spawned, {} retired after idle timeout",
+                 self.threads_spawned, self.threads_retired)?;
+        writeln!(f, "tasks completed: {}", self.tasks_completed)?;
+        writeln!(f)?;
+        writeln!(f, "{:>12} {:>14} {:>14}", "", "queue wait", "run time")?;
+
+        for &(name, wait, run) in [
+            ("min", self.queue_wait.min, self.run_time.min),
+            ("mean", self.queue_wait.mean(), self.run_time.mean()),
+            ("max", self.queue_wait.max, self.run_time.max),
+        ].iter() {
+            writeln!(f, "{:>12} {:>14} {:>14}",
+                     name, format!("{:?}", wait), format!("{:?}", run))?;
+        }
+
+        let used = |h: &Histogram| h.buckets.iter().position(|&c| c > 0)
+            .map(|first| (first, h.buckets.iter().rposition(|&c| c > 0).unwrap()));
+        let range = match (used(&self.queue_wait), used(&self.run_time)) {
+            (None, None) => return Ok(()),
+            (Some(r), None) | (None, Some(r)) => r,
+            (Some(a), Some(b)) => (std::cmp::min(a.0, b.0), std::cmp::max(a.1, b.1)),
+        };
+
+        for i in range.0..range.1 + 1 {
+            let bound = if i == HISTOGRAM_BUCKETS - 1 {
+                format!(">= {}us", 1u64 << (i - 1))
+            } else {
+                format!("< {}us", 1u64 << i)
+            };
+            writeln!(f, "{:>12} {:>14} {:>14}",
+                     bound, self.queue_wait.buckets[i], self.run_time.buckets[i])?;
+        }
+
+        Ok(())
+    }
+}
+
 #[derive(Clone, Debug)]
 struct Power {
     value: u64,
@@ -237,12 +501,17 @@ fn test_workqueue<F: ?Sized>(workqueue: Arc<Workqueue<Power, F>>)
     }
 }
 
+fn calculate_power(p: &Power) -> &Power {
+    let mut _sum = p.value;
+    for _ in 1..p.power {
+        _sum *= p.value;
+    }
+    p
+}
+
 fn main() {
     let wq: Workqueue<Power, _> = Workqueue::new(Box::new(move |p: Power| {
-        let mut _sum = p.value;
-        for _ in 1..p.power {
-            _sum *= p.value;
-        }
+        calculate_power(&p);
         p
     }), 4);
     let wq = Arc::new(wq);
@@ -265,4 +534,91 @@ fn main() {
     for (i, per_worker) in result {
         println!("worker {:2}, calculated {} powers", i, per_worker.len());
     }
+
+    println!();
+    print!("{}", wq.metrics());
+
+    // the tasks of a scoped workqueue borrow the powers instead of owning them
+    let powers = (0..ITERATIONS).map(|_| Power::new()).collect::<Vec<_>>();
+
+    let result = match Workqueue::scoped(Box::new(calculate_power), 4, |wq| {
+        for p in powers.iter() {
+            if let Err(e) = wq.add_task(p) {
+                panic!("Failed to add task to scoped workqueue. {}", e);
+            }
+        }
+    }) {
+        Err(e) => panic!("Scoped workqueue failed: {}", e),
+        Ok(result) => result,
+    };
+
+    println!();
+    println!("scoped workqueue calculated {} powers",
+             result.iter().map(|per_worker| per_worker.len()).sum::<usize>());
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Histogram, HISTOGRAM_BUCKETS, Workqueue};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[test]
+    fn histogram_buckets() {
+        assert_eq!(Histogram::bucket_index(Duration::from_millis(0)), 0);
+        assert_eq!(Histogram::bucket_index(Duration::new(0, 999)), 0);
+        assert_eq!(Histogram::bucket_index(Duration::from_micros(1)), 1);
+        assert_eq!(Histogram::bucket_index(Duration::from_micros(3)), 2);
+        assert_eq!(Histogram::bucket_index(Duration::from_micros(4)), 3);
+        assert_eq!(Histogram::bucket_index(Duration::from_secs(1_000_000)), HISTOGRAM_BUCKETS - 1);
+    }
+
+    #[test]
+    fn histogram_summary() {
+        let mut histogram = Histogram::new();
+        assert_eq!(histogram.mean(), Duration::from_secs(0));
+
+        histogram.record(Duration::from_micros(30));
+        histogram.record(Duration::from_micros(10));
+        histogram.record(Duration::from_micros(20));
+
+        assert_eq!(histogram.count, 3);
+        assert_eq!(histogram.min, Duration::from_micros(10));
+        assert_eq!(histogram.max, Duration::from_micros(30));
+        assert_eq!(histogram.mean(), Duration::from_micros(20));
+        assert_eq!(histogram.buckets.iter().sum::<u64>(), 3);
+    }
+
+    #[test]
+    fn scoped_workqueue_borrows_and_finishes() {
+        let values = (1..101).collect::<Vec<usize>>();
+        let sum = AtomicUsize::new(0);
+
+        let result = Workqueue::scoped(Box::new(|i: usize| {
+            sum.fetch_add(values[i], Ordering::SeqCst);
+            i
+        }), 4, |wq| {
+            for i in 0..values.len() {
+                wq.add_task(i).unwrap();
+            }
+        }).unwrap();
+
+        assert_eq!(sum.load(Ordering::SeqCst), 5050);
+        assert_eq!(result.iter().map(|per_worker| per_worker.len()).sum::<usize>(), 100);
+    }
+
+    #[test]
+    #[should_panic]
+    fn scoped_workqueue_propagates_panics() {
+        let _ = Workqueue::scoped(Box::new(|i: usize| {
+            if i == 3 {
+                panic!("task {} failed", i);
+            }
+            i
+        }), 2, |wq| {
+            for i in 0..10 {
+                wq.add_task(i).unwrap();
+            }
+        });
+    }
 }